@@ -1,9 +1,24 @@
-use clap::{Command, Arg};
+use clap::{Command, Arg, ArgAction};
 
 pub fn build_cli() -> Command {
     Command::new("smed")
         .version("0.1.0")
         .about("Manages infrastructure")
+        .arg(
+            Arg::new("output").short('o').long("output").global(true).default_value("text").value_parser(["text", "json"]).help("Print a JSON result on stdout and progress on stderr with json")
+        )
+        .arg(
+            Arg::new("verbose").short('v').long("verbose").global(true).action(ArgAction::SetTrue).conflicts_with("quiet").help("Also print the commands smed runs and their output")
+        )
+        .arg(
+            Arg::new("quiet").short('q').long("quiet").global(true).action(ArgAction::SetTrue).help("Only print warnings and errors")
+        )
+        .arg(
+            Arg::new("log-file").long("log-file").global(true).help("Append every message, including the full output of the commands smed runs, to this file as JSON lines")
+        )
+        .arg(
+            Arg::new("no-color").long("no-color").global(true).action(ArgAction::SetTrue).help("Disable colors, also disabled when NO_COLOR is set")
+        )
         .subcommand(
             Command::new("init")
                 .about("Initializes the config")
@@ -13,6 +28,9 @@ pub fn build_cli() -> Command {
                 .arg(
                     Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
                 )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
                 .arg(
                     Arg::new("provider").short('p').long("provider")
                     .required(false)
@@ -27,5 +45,117 @@ pub fn build_cli() -> Command {
                 .arg(
                     Arg::new("terraform-directory").short('t').long("terraform-directory").required(false).default_value("./terraform").help("The directory to use for the terraform files")
                 )
+                .arg(
+                    Arg::new("spec").short('s').long("spec").required(false).default_value("./smed.yaml").help("The path to the cluster spec file")
+                )
+                .arg(
+                    Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
+                )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
+                .arg(
+                    Arg::new("region").short('r').long("region").required(false).help("The region to deploy to, overrides the spec's region")
+                )
+                .arg(
+                    Arg::new("template-dir").long("template-dir").required(false).help("A directory with custom *.tf.tera templates, overrides the spec's template_dir")
+                )
+                .arg(
+                    Arg::new("rke2-version").long("rke2-version").required(false).conflicts_with("rke2-channel").help("The RKE2 release to install, e.g. v1.30.4+rke2r1, overrides the spec's rke2 settings")
+                )
+                .arg(
+                    Arg::new("rke2-channel").long("rke2-channel").required(false).help("The RKE2 channel to install from, e.g. stable or v1.30, overrides the spec's rke2 settings")
+                )
+                .arg(
+                    Arg::new("rke2-artifacts").long("rke2-artifacts").required(false).conflicts_with("rke2-channel").help("A directory with the RKE2 artifacts for an air-gapped install, overrides the spec's rke2.artifacts_dir")
+                )
+                .arg(
+                    Arg::new("concurrency").short('c').long("concurrency").required(false).default_value("5").value_parser(clap::value_parser!(usize)).help("How many nodes to bootstrap at the same time")
+                )
+                .arg(
+                    Arg::new("force").short('f').long("force").action(ArgAction::SetTrue).help("Ignore the deploy journal and replay every step from scratch")
+                )
+                .arg(
+                    Arg::new("dry-run").long("dry-run").action(ArgAction::SetTrue).help("Show the Terraform plan and the commands each node would run, without changing anything")
+                )
+                .arg(
+                    Arg::new("merge-kubeconfig").long("merge-kubeconfig").action(ArgAction::SetTrue).help("Merge the cluster's kubeconfig into ~/.kube/config")
+                )
+        )
+        .subcommand(
+            Command::new("destroy")
+                .about("Destroys everything created by deploy")
+                .arg(
+                    Arg::new("terraform-directory").short('t').long("terraform-directory").required(false).default_value("./terraform").help("The directory to use for the terraform files")
+                )
+                .arg(
+                    Arg::new("spec").short('s').long("spec").required(false).default_value("./smed.yaml").help("The path to the cluster spec file")
+                )
+                .arg(
+                    Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
+                )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
+                .arg(
+                    Arg::new("yes").short('y').long("yes").action(ArgAction::SetTrue).help("Skip the confirmation prompt")
+                )
+        )
+        .subcommand(
+            Command::new("kubeconfig")
+                .about("Downloads the cluster's kubeconfig")
+                .arg(
+                    Arg::new("terraform-directory").short('t').long("terraform-directory").required(false).default_value("./terraform").help("The directory to use for the terraform files")
+                )
+                .arg(
+                    Arg::new("spec").short('s').long("spec").required(false).default_value("./smed.yaml").help("The path to the cluster spec file")
+                )
+                .arg(
+                    Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
+                )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
+                .arg(
+                    Arg::new("merge").short('m').long("merge").action(ArgAction::SetTrue).help("Merge the kubeconfig into ~/.kube/config")
+                )
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Reports the health of every node in the cluster")
+                .arg(
+                    Arg::new("terraform-directory").short('t').long("terraform-directory").required(false).default_value("./terraform").help("The directory to use for the terraform files")
+                )
+                .arg(
+                    Arg::new("spec").short('s').long("spec").required(false).default_value("./smed.yaml").help("The path to the cluster spec file")
+                )
+                .arg(
+                    Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
+                )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Upgrades RKE2 on every node, one node at a time")
+                .arg(
+                    Arg::new("terraform-directory").short('t').long("terraform-directory").required(false).default_value("./terraform").help("The directory to use for the terraform files")
+                )
+                .arg(
+                    Arg::new("spec").short('s').long("spec").required(false).default_value("./smed.yaml").help("The path to the cluster spec file")
+                )
+                .arg(
+                    Arg::new("env-path").short('e').long("env-path").required(false).default_value("./.env").help("The path to the environment file")
+                )
+                .arg(
+                    Arg::new("env").long("env").required(false).help("The environment to use, e.g. staging: its own state under the terraform directory, .env.<env> and smed.<env>.yaml on top of the shared files")
+                )
+                .arg(
+                    Arg::new("rke2-version").long("rke2-version").required(false).help("The RKE2 version to upgrade to, e.g. v1.30.4+rke2r1, defaults to the spec's rke2.version")
+                )
+                .arg(
+                    Arg::new("rke2-artifacts").long("rke2-artifacts").required(false).help("A directory with the RKE2 artifacts of that version for an air-gapped upgrade, defaults to the spec's rke2.artifacts_dir")
+                )
         )
 }
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::cmd::ssh::expand_tilde;
+use crate::error::SmedError;
+
+const ARCH: &str = "amd64";
+
+// Where the artifacts are uploaded on every node. /var/tmp survives a reboot
+// halfway through a deploy.
+const REMOTE_DIRECTORY: &str = "/var/tmp/smed-rke2-artifacts";
+
+// A file copied from the machine running smed to a node.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub local: PathBuf,
+    pub remote: String,
+    pub sha256: String,
+}
+
+// The files of an RKE2 release staged for an air-gapped install: the install
+// script, the release tarball, the image archives and the release's
+// checksums file, as published on the RKE2 GitHub releases.
+#[derive(Debug, Clone)]
+pub struct Artifacts {
+    pub uploads: Vec<Upload>,
+}
+
+impl Artifacts {
+    // Reads the artifacts in `directory` and checks the tarball and every image
+    // archive against the checksums file, so a broken download is caught
+    // before anything reaches a node.
+    pub fn load(directory: &str) -> Result<Artifacts, SmedError> {
+        let directory = PathBuf::from(expand_tilde(directory));
+        let invalid = |message: String| SmedError::ConfigInvalid(format!("Invalid RKE2 artifacts in {}: {}", directory.display(), message));
+
+        let checksums_name = format!("sha256sum-{}.txt", ARCH);
+        let tarball_name = format!("rke2.linux-{}.tar.gz", ARCH);
+
+        let checksums = fs::read_to_string(directory.join(&checksums_name))
+            .map_err(|e| invalid(format!("failed to read {}: {}", checksums_name, e)))?;
+
+        // `<sha256>  <file>` per line, as written by sha256sum
+        let expected: HashMap<&str, &str> = checksums.lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .map(|(sha256, name)| (name.trim().trim_start_matches('*'), sha256))
+            .collect();
+
+        let mut images: Vec<String> = fs::read_dir(&directory)
+            .map_err(|e| invalid(e.to_string()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("rke2-images") && name.contains(&format!(".linux-{}.tar", ARCH)))
+            .collect();
+        images.sort();
+
+        if images.is_empty() {
+            return Err(invalid(format!("no rke2-images*.linux-{}.tar.* image archive", ARCH)));
+        }
+
+        let mut names = vec!["install.sh".to_string(), tarball_name.clone()];
+        names.extend(images.iter().cloned());
+        names.push(checksums_name.clone());
+
+        let mut uploads = Vec::new();
+
+        for name in names {
+            let local = directory.join(&name);
+
+            if !local.is_file() {
+                return Err(invalid(format!("{} is missing", name)));
+            }
+
+            let sha256 = file_sha256(&local).map_err(|e| invalid(format!("failed to read {}: {}", name, e)))?;
+
+            if name == tarball_name || images.contains(&name) {
+                match expected.get(name.as_str()) {
+                    Some(expected) if *expected == sha256 => {},
+                    Some(_) => return Err(invalid(format!("{} does not match its checksum in {}", name, checksums_name))),
+                    None => return Err(invalid(format!("{} is not listed in {}", name, checksums_name))),
+                }
+            }
+
+            uploads.push(Upload { remote: format!("{}/{}", REMOTE_DIRECTORY, name), local, sha256 });
+        }
+
+        Ok(Artifacts { uploads })
+    }
+
+    // Checks every uploaded file on the node against the checksums computed
+    // here, so a truncated upload never gets installed.
+    pub fn verify_command(&self) -> String {
+        let lines: Vec<String> = self.uploads.iter()
+            .map(|upload| format!("'{}  {}'", upload.sha256, upload.remote))
+            .collect();
+
+        format!("printf '%s\\n' {} | sha256sum --check --quiet -", lines.join(" "))
+    }
+
+    pub fn install_command(&self, install_type: &str) -> String {
+        format!("sudo sh -c 'INSTALL_RKE2_ARTIFACT_PATH={0} INSTALL_RKE2_TYPE=\"{1}\" sh {0}/install.sh'", REMOTE_DIRECTORY, install_type)
+    }
+}
+
+fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifacts_are_checked_against_the_release_checksums() {
+        let directory = std::env::temp_dir().join(format!("smed-artifacts-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        fs::write(directory.join("install.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(directory.join("rke2.linux-amd64.tar.gz"), "tarball").unwrap();
+        fs::write(directory.join("rke2-images.linux-amd64.tar.zst"), "images").unwrap();
+        fs::write(directory.join("sha256sum-amd64.txt"), format!(
+            "{}  rke2.linux-amd64.tar.gz\n{}  rke2-images.linux-amd64.tar.zst\n",
+            file_sha256(&directory.join("rke2.linux-amd64.tar.gz")).unwrap(),
+            file_sha256(&directory.join("rke2-images.linux-amd64.tar.zst")).unwrap(),
+        )).unwrap();
+
+        let artifacts = Artifacts::load(directory.to_str().unwrap()).unwrap();
+        let remotes: Vec<&str> = artifacts.uploads.iter().map(|upload| upload.remote.as_str()).collect();
+
+        assert_eq!(remotes, [
+            "/var/tmp/smed-rke2-artifacts/install.sh",
+            "/var/tmp/smed-rke2-artifacts/rke2.linux-amd64.tar.gz",
+            "/var/tmp/smed-rke2-artifacts/rke2-images.linux-amd64.tar.zst",
+            "/var/tmp/smed-rke2-artifacts/sha256sum-amd64.txt",
+        ]);
+        assert!(artifacts.verify_command().contains("  /var/tmp/smed-rke2-artifacts/install.sh'"));
+
+        fs::write(directory.join("rke2-images.linux-amd64.tar.zst"), "truncated").unwrap();
+
+        let error = Artifacts::load(directory.to_str().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("rke2-images.linux-amd64.tar.zst does not match its checksum in sha256sum-amd64.txt"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
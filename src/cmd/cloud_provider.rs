@@ -1,10 +1,16 @@
-use std::process::Command;
 use std::fmt;
+use std::fs;
 use std::str::FromStr;
 
+use serde::Deserialize;
+
+use crate::cmd::runner::{CommandRunner, Invocation};
 use crate::config::Config;
+use crate::error::SmedError;
+use crate::output::info;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CloudProvider {
     AWS,
     GCP,
@@ -35,6 +41,37 @@ impl FromStr for CloudProvider {
     }
 }
 
+impl CloudProvider {
+    pub fn template_name(&self) -> &'static str {
+        match self {
+            CloudProvider::AWS => "main.tf.tera",
+            CloudProvider::GCP => "gcp.tf.tera",
+            CloudProvider::AZURE => "azure.tf.tera",
+        }
+    }
+
+    // AMI ids differ per region, so on AWS the template looks up Canonical's
+    // Ubuntu 22.04 AMI in the deploy region instead.
+    pub fn default_image(&self) -> Option<&'static str> {
+        match self {
+            CloudProvider::AWS => None,
+            CloudProvider::GCP => Some("ubuntu-os-cloud/ubuntu-2204-lts"),
+            CloudProvider::AZURE => Some("Canonical:0001-com-ubuntu-server-jammy:22_04-lts-gen2:latest"),
+        }
+    }
+
+    pub fn default_instance_type(&self, role: &str) -> &'static str {
+        match (self, role) {
+            (CloudProvider::AWS, "worker") => "t2.micro",
+            (CloudProvider::AWS, _) => "t2.medium",
+            (CloudProvider::GCP, "worker") => "e2-small",
+            (CloudProvider::GCP, _) => "e2-medium",
+            (CloudProvider::AZURE, "worker") => "Standard_B1ms",
+            (CloudProvider::AZURE, _) => "Standard_B2s",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum CloudProviderRegion {
     UsEast1,
@@ -51,6 +88,19 @@ impl fmt::Display for CloudProviderRegion {
     }
 }
 
+impl CloudProviderRegion {
+    pub fn name_for(&self, provider: &CloudProvider) -> &'static str {
+        match (provider, self) {
+            (CloudProvider::AWS, CloudProviderRegion::UsEast1) => "us-east-1",
+            (CloudProvider::AWS, CloudProviderRegion::UsEast2) => "us-east-2",
+            (CloudProvider::GCP, CloudProviderRegion::UsEast1) => "us-east1",
+            (CloudProvider::GCP, CloudProviderRegion::UsEast2) => "us-east4",
+            (CloudProvider::AZURE, CloudProviderRegion::UsEast1) => "eastus",
+            (CloudProvider::AZURE, CloudProviderRegion::UsEast2) => "eastus2",
+        }
+    }
+}
+
 impl FromStr for CloudProviderRegion {
     type Err = String;
 
@@ -74,70 +124,184 @@ impl CloudProviderAuthParams {
 }
 
 trait CloudProviderAuth {
-    fn auth(&self, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>>;
+    fn auth(&self, runner: &dyn CommandRunner, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>>;
 }
 
-pub fn auth(params: CloudProviderAuthParams, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+pub fn auth(runner: &dyn CommandRunner, params: CloudProviderAuthParams, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let result = match params.provider {
-        CloudProvider::AWS => AwsCloudProviderAuth.auth(params.region, config),
-        CloudProvider::GCP => GcpCloudProviderAuth.auth(params.region, config),
-        CloudProvider::AZURE => AzureCloudProviderAuth.auth(params.region, config),
+        CloudProvider::AWS => AwsCloudProviderAuth.auth(runner, params.region, config),
+        CloudProvider::GCP => GcpCloudProviderAuth.auth(runner, params.region, config),
+        CloudProvider::AZURE => AzureCloudProviderAuth.auth(runner, params.region, config),
     };
     result
 }
 
+pub fn terraform_env(provider: &CloudProvider, config: &Config) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let env = match provider {
+        CloudProvider::AWS => vec![
+            (String::from("AWS_ACCESS_KEY_ID"), Config::required(&config.aws_access_key, "AWS_ACCESS_KEY_ID")?.to_string()),
+            (String::from("AWS_SECRET_ACCESS_KEY"), Config::required(&config.aws_secret_key, "AWS_SECRET_ACCESS_KEY")?.to_string()),
+        ],
+        CloudProvider::GCP => {
+            let account = GcpServiceAccount::load(config)?;
+
+            vec![
+                (String::from("GOOGLE_APPLICATION_CREDENTIALS"), account.path),
+                (String::from("GOOGLE_PROJECT"), account.project_id),
+            ]
+        },
+        CloudProvider::AZURE => {
+            let principal = AzureServicePrincipal::load(config)?;
+
+            vec![
+                (String::from("ARM_TENANT_ID"), principal.tenant_id.to_string()),
+                (String::from("ARM_CLIENT_ID"), principal.client_id.to_string()),
+                (String::from("ARM_CLIENT_SECRET"), principal.client_secret.to_string()),
+                (String::from("ARM_SUBSCRIPTION_ID"), principal.subscription_id.to_string()),
+            ]
+        },
+    };
+
+    Ok(env)
+}
+
 struct AwsCloudProviderAuth;
 struct GcpCloudProviderAuth;
 struct AzureCloudProviderAuth;
 
 impl CloudProviderAuth for AwsCloudProviderAuth {
-    fn auth(&self, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-        let set_region = Command::new("aws")
-            .arg("configure")
-            .arg("set")
-            .arg("region")
-            .arg(region.to_string())
-            .arg("--")
-            .output()?;
-
-        let set_access_key = Command::new("aws")
-            .arg("configure")
-            .arg("set")
-            .arg("aws_access_key_id")
-            .arg(&config.aws_access_key)
-            .arg("--")
-            .output()?;
-
-        let set_secret_key = Command::new("aws")
-            .arg("configure")
-            .arg("set")
-            .arg("aws_secret_access_key")
-            .arg(&config.aws_secret_key)
-            .arg("--")
-            .output()?;
-
-        if set_region.status.success() && set_access_key.status.success() && set_secret_key.status.success() {
-            println!("Authenticated to AWS in region: {:?}", region.to_string());
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&set_region.stderr);
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, stderr)))
+    fn auth(&self, runner: &dyn CommandRunner, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        let region_name = region.to_string();
+
+        let set_region = runner.run(&Invocation::new("aws")
+            .args(["configure", "set", "region", &region_name, "--"]))?;
+
+        let access_key = Config::required(&config.aws_access_key, "AWS_ACCESS_KEY_ID")?;
+        let set_access_key = runner.run(&Invocation::new("aws")
+            .args(["configure", "set", "aws_access_key_id", access_key, "--"])
+            .secret(access_key))?;
+
+        let secret_key = Config::required(&config.aws_secret_key, "AWS_SECRET_ACCESS_KEY")?;
+        let set_secret_key = runner.run(&Invocation::new("aws")
+            .args(["configure", "set", "aws_secret_access_key", secret_key, "--"])
+            .secret(secret_key))?;
+
+        match [set_region, set_access_key, set_secret_key].into_iter().find(|output| !output.success()) {
+            None => {
+                info!("Authenticated to AWS in region: {:?}", region.to_string());
+                Ok(())
+            },
+            Some(failed) => Err(SmedError::AuthFailed {
+                provider: CloudProvider::AWS.to_string(),
+                message: failed.stderr,
+            }.into()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GcpServiceAccount {
+    #[serde(rename = "type")]
+    account_type: String,
+    project_id: String,
+    client_email: String,
+    #[serde(skip)]
+    path: String,
+}
+
+impl GcpServiceAccount {
+    fn load(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Config::required(&config.gcp_credentials_path, "GOOGLE_APPLICATION_CREDENTIALS")?;
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| SmedError::ConfigInvalid(format!("Failed to read GCP service account file {}: {}", path, e)))?;
+
+        let mut account: GcpServiceAccount = serde_json::from_str(&contents)
+            .map_err(|e| SmedError::ConfigInvalid(format!("Invalid GCP service account file {}: {}", path, e)))?;
+
+        if account.account_type != "service_account" {
+            return Err(SmedError::ConfigInvalid(format!("{} is not a service account key (type is '{}')", path, account.account_type)).into());
         }
+
+        if let Some(project_id) = config.gcp_project_id.as_deref().filter(|p| !p.trim().is_empty()) {
+            account.project_id = project_id.to_string();
+        }
+
+        account.path = path.to_string();
+
+        Ok(account)
     }
 }
 
 impl CloudProviderAuth for GcpCloudProviderAuth {
-    fn auth(&self, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Authenticating to GCP in region: {:?} with config: {:?}", region, config);
-        // Insert GCP-specific logic here
+    fn auth(&self, runner: &dyn CommandRunner, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        let account = GcpServiceAccount::load(config)?;
+        let region_name = region.name_for(&CloudProvider::GCP);
+
+        let commands = [
+            vec!["auth", "activate-service-account", &account.client_email, "--key-file", &account.path],
+            vec!["config", "set", "project", &account.project_id],
+            vec!["config", "set", "compute/region", region_name],
+        ];
+
+        for args in commands {
+            let output = runner.run(&Invocation::new("gcloud").args(&args))?;
+
+            if !output.success() {
+                return Err(SmedError::AuthFailed {
+                    provider: CloudProvider::GCP.to_string(),
+                    message: format!("gcloud {} failed: {}", args.join(" "), output.stderr),
+                }.into());
+            }
+        }
+
+        info!("Authenticated to GCP project {} as {} in region: {:?}", account.project_id, account.client_email, region_name);
         Ok(())
     }
 }
 
+struct AzureServicePrincipal<'a> {
+    tenant_id: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+    subscription_id: &'a str,
+}
+
+impl<'a> AzureServicePrincipal<'a> {
+    fn load(config: &'a Config) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            tenant_id: Config::required(&config.azure_tenant_id, "AZURE_TENANT_ID")?,
+            client_id: Config::required(&config.azure_client_id, "AZURE_CLIENT_ID")?,
+            client_secret: Config::required(&config.azure_client_secret, "AZURE_CLIENT_SECRET")?,
+            subscription_id: Config::required(&config.azure_subscription_id, "AZURE_SUBSCRIPTION_ID")?,
+        })
+    }
+}
+
 impl CloudProviderAuth for AzureCloudProviderAuth {
-    fn auth(&self, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Authenticating to Azure in region: {:?} with config: {:?}", region, config);
-        // Insert Azure-specific logic here
+    fn auth(&self, runner: &dyn CommandRunner, region: CloudProviderRegion, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        let principal = AzureServicePrincipal::load(config)?;
+        let region_name = region.name_for(&CloudProvider::AZURE);
+
+        let login = runner.run(&Invocation::new("az").args([
+            "login", "--service-principal",
+            "--username", principal.client_id,
+            "--password", principal.client_secret,
+            "--tenant", principal.tenant_id,
+            "--output", "none",
+        ]).secret(principal.client_secret))?;
+
+        if !login.success() {
+            return Err(SmedError::AuthFailed { provider: CloudProvider::AZURE.to_string(), message: format!("az login failed: {}", login.stderr) }.into());
+        }
+
+        let set_subscription = runner.run(&Invocation::new("az").args(["account", "set", "--subscription", principal.subscription_id]))?;
+
+        if !set_subscription.success() {
+            return Err(SmedError::AuthFailed { provider: CloudProvider::AZURE.to_string(), message: format!("az account set failed: {}", set_subscription.stderr) }.into());
+        }
+
+        info!("Authenticated to Azure subscription {} in region: {:?}", principal.subscription_id, region_name);
         Ok(())
     }
 }
@@ -146,15 +310,75 @@ impl CloudProviderAuth for AzureCloudProviderAuth {
 mod tests {
     use super::*;
 
+    use crate::cmd::runner::ReplayRunner;
+
     #[test]
     fn test_auth() {
-        let result = auth(CloudProviderAuthParams {
+        let replay = ReplayRunner::from_json(r#"[
+            {"program": "aws", "args": ["configure", "set", "region", "us-east-1", "--"]},
+            {"program": "aws", "args": ["configure", "set", "aws_access_key_id", "********", "--"]},
+            {"program": "aws", "args": ["configure", "set", "aws_secret_access_key", "********", "--"]}
+        ]"#).unwrap();
+
+        auth(&replay, CloudProviderAuthParams {
             provider: CloudProvider::AWS,
             region: CloudProviderRegion::UsEast1,
         }, &Config {
-            aws_access_key: "test".to_string(),
-            aws_secret_key: "test".to_string(),
+            aws_access_key: Some("test".to_string()),
+            aws_secret_key: Some("test".to_string()),
+            ..Default::default()
         }).unwrap();
-        assert_eq!(result, ());
+        assert!(replay.unused().is_empty());
+
+        let failing = ReplayRunner::from_json(r#"[
+            {"program": "aws", "args": ["configure", "set", "region", "us-east-1", "--"]},
+            {"program": "aws", "args": ["configure", "set", "aws_access_key_id", "********", "--"], "stderr": "denied", "exit_code": 1},
+            {"program": "aws", "args": ["configure", "set", "aws_secret_access_key", "********", "--"]}
+        ]"#).unwrap();
+
+        let error = auth(&failing, CloudProviderAuthParams::new(CloudProvider::AWS, CloudProviderRegion::UsEast1), &Config {
+            aws_access_key: Some("test".to_string()),
+            aws_secret_key: Some("test".to_string()),
+            ..Default::default()
+        }).unwrap_err();
+        assert_eq!(crate::error::exit_code(error.as_ref()), 4);
+    }
+
+    #[test]
+    fn test_azure_auth_and_terraform_env() {
+        let config = Config {
+            azure_tenant_id: Some("tenant".to_string()),
+            azure_client_id: Some("client".to_string()),
+            azure_client_secret: Some("s3cr3t".to_string()),
+            azure_subscription_id: Some("subscription".to_string()),
+            ..Default::default()
+        };
+
+        let replay = ReplayRunner::from_json(r#"[
+            {"program": "az", "args": ["login", "--service-principal", "--username", "client", "--password", "********", "--tenant", "tenant", "--output", "none"]},
+            {"program": "az", "args": ["account", "set", "--subscription", "subscription"]}
+        ]"#).unwrap();
+
+        auth(&replay, CloudProviderAuthParams::new(CloudProvider::AZURE, CloudProviderRegion::UsEast2), &config).unwrap();
+        assert!(replay.unused().is_empty());
+
+        let env = terraform_env(&CloudProvider::AZURE, &config).unwrap();
+        assert_eq!(env, [
+            ("ARM_TENANT_ID".to_string(), "tenant".to_string()),
+            ("ARM_CLIENT_ID".to_string(), "client".to_string()),
+            ("ARM_CLIENT_SECRET".to_string(), "s3cr3t".to_string()),
+            ("ARM_SUBSCRIPTION_ID".to_string(), "subscription".to_string()),
+        ]);
+
+        let failing = ReplayRunner::from_json(r#"[
+            {"program": "az", "args": ["login", "--service-principal", "--username", "client", "--password", "********", "--tenant", "tenant", "--output", "none"], "stderr": "AADSTS7000215", "exit_code": 1}
+        ]"#).unwrap();
+
+        let error = auth(&failing, CloudProviderAuthParams::new(CloudProvider::AZURE, CloudProviderRegion::UsEast1), &config).unwrap_err();
+        assert_eq!(crate::error::exit_code(error.as_ref()), 4);
+        assert!(error.to_string().contains("az login failed: AADSTS7000215"));
+
+        let missing = terraform_env(&CloudProvider::AZURE, &Config { azure_client_secret: None, ..config }).unwrap_err();
+        assert!(missing.to_string().contains("AZURE_CLIENT_SECRET"));
     }
 }
\ No newline at end of file
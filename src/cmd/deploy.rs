@@ -1,22 +1,210 @@
 use clap::ArgMatches;
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::cmd::terraform::TerraformClient;
-use crate::cmd::kube_manager::KubeManager;
+use serde_json::json;
 
-pub fn handle(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    // let env = args.get_one::<String>("env").unwrap();
+use crate::cmd::airgap::Artifacts;
+use crate::cmd::environment::Environment;
+use crate::cmd::journal::Journal;
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::terraform::{TerraformClient, TerraformOutput, TerraformValue};
+use crate::cmd::token;
+use crate::cmd::kube_manager::{output_ip, rancher_hostname, KubeManager, NodeReport};
+use crate::cmd::kubeconfig;
+use crate::cmd::cloud_provider;
+use crate::config::ClusterSpec;
+use crate::output::{self, info, success, warning};
 
-    let terraform_directory = args.get_one::<String>("terraform-directory").unwrap();
+pub fn handle(args: &ArgMatches, runner: Arc<dyn CommandRunner>) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = Environment::from_args(args)?;
 
-    let output = TerraformClient::apply(terraform_directory)?;
+    let terraform_directory = &environment.terraform_directory(args);
 
-    let common_token = "my-manual-token";
+    let mut spec = environment.spec(args)?;
 
-    KubeManager::setup_rancher_cluster(&output, &common_token)?;
+    if let Some(region) = args.get_one::<String>("region") {
+        spec.region = region.to_lowercase();
+    }
 
-    KubeManager::setup_etcd_cluster(&output, &common_token)?;
+    if let Some(template_directory) = args.get_one::<String>("template-dir") {
+        spec.template_dir = Some(template_directory.clone());
+    }
 
-    KubeManager::setup_control_plane_cluster(&output, &common_token)?;
+    if let Some(version) = args.get_one::<String>("rke2-version") {
+        spec.rke2.version = Some(version.clone());
+        spec.rke2.channel = None;
+    }
+
+    if let Some(channel) = args.get_one::<String>("rke2-channel") {
+        spec.rke2.version = None;
+        spec.rke2.channel = Some(channel.clone());
+    }
+
+    if let Some(artifacts_directory) = args.get_one::<String>("rke2-artifacts") {
+        spec.rke2.artifacts_dir = Some(artifacts_directory.clone());
+    }
+
+    spec.validate()?;
+
+    // Checked before anything is created, a bad download fails fast
+    let artifacts = spec.rke2.artifacts_dir.as_deref().map(Artifacts::load).transpose()?;
+
+    let config = environment.config(args)?;
+
+    let terraform_env = cloud_provider::terraform_env(&spec.provider, &config)?;
+
+    let terraform = TerraformClient::new(runner.clone());
+
+    if args.get_flag("dry-run") {
+        return dry_run(&terraform, runner.as_ref(), terraform_directory, &spec, artifacts, &terraform_env);
+    }
+
+    let journal = Arc::new(Journal::load(terraform_directory)?);
+
+    if args.get_flag("force") {
+        journal.reset()?;
+    }
+
+    let started = Instant::now();
+    let output = terraform.apply(terraform_directory, &spec, &terraform_env, &journal)?;
+    output::timing("terraform", started);
+
+    let common_token = token::resolve(terraform_directory, &output)?;
+    let bootstrap_password = token::bootstrap_password(terraform_directory)?;
+
+    let concurrency = *args.get_one::<usize>("concurrency").unwrap();
+
+    let kube_manager = Arc::new(KubeManager::new(runner.as_ref(), &spec.ssh).with_concurrency(concurrency).with_journal(journal).with_secret(&common_token).with_bootstrap_password(&bootstrap_password).with_rke2(&spec.rke2).with_rancher(&spec.rancher).with_artifacts(artifacts.clone()));
+
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    let started = Instant::now();
+    let setup = runtime.block_on(kube_manager.setup_cluster(&output, &common_token));
+    output::timing("bootstrap", started);
+    output::record("nodes", reported_nodes(kube_manager.reports(), artifacts.is_some()));
+    setup?;
+
+    let merge_into = args.get_flag("merge-kubeconfig").then(kubeconfig::default_path);
+
+    kubeconfig::export(&kube_manager, &output, &spec, terraform_directory, merge_into.as_deref())?;
+
+    if artifacts.is_some() {
+        warning!("⚠ Air-gapped install, Rancher was not installed: the rancher node only runs RKE2 and there is no dashboard");
+        return Ok(());
+    }
+
+    print_dashboard(&output, terraform_directory, &bootstrap_password)
+}
+
+// The nodes the deploy result lists. The Rancher charts and images come from
+// the internet, so an air-gapped rancher node doesn't serve Rancher and is
+// left out unless it failed.
+fn reported_nodes(reports: Vec<NodeReport>, air_gapped: bool) -> Vec<NodeReport> {
+    reports.into_iter().filter(|report| !air_gapped || report.role != "Rancher" || report.status == "failed").collect()
+}
+
+fn print_dashboard(output: &TerraformOutput, terraform_directory: &str, bootstrap_password: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("https://{}", rancher_hostname(&output_ip(output, "rancher_ip")?));
+    let password_path = token::bootstrap_password_path(terraform_directory);
+
+    success!("✔ Rancher dashboard: {}", url);
+    info!("  Username: admin");
+    output::show(&format!("  Password: {}", bootstrap_password));
+    info!("  The password is saved in {}, change it after the first login", password_path.display());
+
+    output::record("rancher", json!({ "url": url, "username": "admin", "password_file": password_path }));
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Shows what a deploy would do without changing anything: the Terraform plan
+// and the commands each node would run. The IPs don't exist yet, so the
+// commands use placeholders named after the Terraform outputs.
+fn dry_run(terraform: &TerraformClient, runner: &dyn CommandRunner, terraform_directory: &str, spec: &ClusterSpec, artifacts: Option<Artifacts>, terraform_env: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    terraform.plan(terraform_directory, spec, terraform_env)?;
+
+    let common_token = token::read(terraform_directory)?.unwrap_or_else(|| "<rke2_token>".to_string());
+
+    let kube_manager = KubeManager::new(runner, &spec.ssh).with_secret(&common_token).with_bootstrap_password("<bootstrap_password>").with_rke2(&spec.rke2).with_rancher(&spec.rancher).with_artifacts(artifacts);
+
+    kube_manager.print_plan(&placeholder_output(spec), &common_token)
+}
+
+fn placeholder_output(spec: &ClusterSpec) -> TerraformOutput {
+    let placeholder = |key: &str| TerraformValue::String { value: format!("<{}>", key) };
+    let placeholders = |key: &str, count: u32| TerraformValue::List { value: (0..count).map(|i| format!("<{}_{}>", key, i)).collect() };
+
+    let mut output = TerraformOutput::new();
+    output.insert("rancher_ip".to_string(), placeholder("rancher_ip"));
+    output.insert("etcd_public_ips".to_string(), placeholders("etcd_public_ip", spec.nodes.etcd.count));
+    output.insert("etcd_private_ips".to_string(), placeholders("etcd_private_ip", spec.nodes.etcd.count));
+    output.insert("control_plane_ips".to_string(), placeholders("control_plane_ip", spec.nodes.control_plane.count));
+    output.insert("worker_ips".to_string(), placeholders("worker_ip", spec.nodes.worker.count));
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::build_cli;
+    use crate::cmd::runner::ReplayRunner;
+    use crate::cmd::state;
+    use std::fs;
+
+    #[test]
+    fn test_deploy_replays_terraform_and_every_node() {
+        let directory = std::env::temp_dir().join(format!("smed-deploy-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let spec_path = directory.join("smed.yaml");
+        fs::write(&spec_path, "name: fixture\nnodes:\n  worker:\n    count: 1\n").unwrap();
+
+        let env_path = directory.join(".env");
+        fs::write(&env_path, "AWS_ACCESS_KEY_ID=test_access_key\nAWS_SECRET_ACCESS_KEY=test_secret_key\n").unwrap();
+
+        let terraform_directory = directory.join("terraform");
+        let terraform_directory = terraform_directory.to_str().unwrap();
+
+        let args = build_cli().get_matches_from([
+            "smed", "deploy",
+            "-t", terraform_directory,
+            "-s", spec_path.to_str().unwrap(),
+            "-e", env_path.to_str().unwrap(),
+        ]);
+
+        state::write_private(&token::bootstrap_password_path(terraform_directory), "fixture-password").unwrap();
+
+        let replay = ReplayRunner::from_json(include_str!("../../tests/fixtures/deploy_aws.json")).unwrap();
+
+        handle(args.subcommand_matches("deploy").unwrap(), Arc::new(replay.clone())).unwrap();
+
+        assert!(replay.unused().is_empty());
+        assert_eq!(token::read(terraform_directory).unwrap().as_deref(), Some("fixture-token"));
+
+        let kubeconfig = fs::read_to_string(state::path(terraform_directory, "kubeconfig.yaml")).unwrap();
+        assert!(kubeconfig.contains("server: https://54.0.0.2:6443"));
+        assert!(kubeconfig.contains("current-context: fixture"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_air_gapped_result_leaves_out_the_rancher_node() {
+        let report = |role: &str, status: &'static str| NodeReport {
+            role: role.to_string(),
+            node: role.to_lowercase(),
+            ip: "54.0.0.1".to_string(),
+            status,
+            error: None,
+            duration_ms: 0,
+            steps: Vec::new(),
+        };
+        let roles = |reports: Vec<NodeReport>| reports.into_iter().map(|report| report.role).collect::<Vec<_>>();
+
+        assert_eq!(roles(reported_nodes(vec![report("Rancher", "ready"), report("Etcd", "ready")], false)), ["Rancher", "Etcd"]);
+        assert_eq!(roles(reported_nodes(vec![report("Rancher", "ready"), report("Etcd", "ready")], true)), ["Etcd"]);
+        assert_eq!(roles(reported_nodes(vec![report("Rancher", "failed"), report("Etcd", "ready")], true)), ["Rancher", "Etcd"]);
+    }
+}
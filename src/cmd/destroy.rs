@@ -0,0 +1,51 @@
+use clap::ArgMatches;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use crate::cmd::cloud_provider;
+use crate::cmd::environment::Environment;
+use crate::cmd::kubeconfig;
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::terraform::TerraformClient;
+use crate::output::{self, Color, info};
+
+pub fn handle(args: &ArgMatches, runner: Arc<dyn CommandRunner>) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = Environment::from_args(args)?;
+
+    let terraform_directory = &environment.terraform_directory(args);
+
+    let spec = environment.spec(args)?;
+    let config = environment.config(args)?;
+
+    let terraform_env = cloud_provider::terraform_env(&spec.provider, &config)?;
+
+    if !args.get_flag("yes") && !confirm(terraform_directory)? {
+        info!("Aborted, nothing was destroyed");
+        output::record("destroyed", false);
+        return Ok(());
+    }
+
+    let terraform = TerraformClient::new(runner);
+
+    terraform.attach(terraform_directory, &spec)?;
+    terraform.destroy(terraform_directory, &terraform_env)?;
+    output::record("destroyed", true);
+
+    kubeconfig::forget(terraform_directory, &spec.name)?;
+
+    TerraformClient::clean(terraform_directory)?;
+
+    Ok(())
+}
+
+fn confirm(terraform_directory: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut prompt = output::human();
+    let warning = format!("⚠ This will destroy every resource managed in {}. Type 'yes' to continue: ", terraform_directory);
+    write!(prompt, "{}", output::paint(Color::Yellow, &warning))?;
+    prompt.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(answer.trim() == "yes")
+}
@@ -0,0 +1,107 @@
+use clap::ArgMatches;
+use std::path::Path;
+
+use crate::cmd::init;
+use crate::cmd::state;
+use crate::config::{ClusterSpec, Config};
+use crate::error::SmedError;
+use crate::output;
+
+// `--env` keeps several environments of one project apart, like staging and
+// production. Each gets its own directory under the terraform directory, so
+// state, outputs, join token and kubeconfig never mix, reads `.env.<env>`
+// before the shared env file and merges `smed.<env>.yaml` over the shared spec.
+pub struct Environment {
+    name: Option<String>,
+}
+
+impl Environment {
+    pub fn from_args(args: &ArgMatches) -> Result<Environment, SmedError> {
+        let name = args.get_one::<String>("env").cloned();
+
+        if let Some(name) = &name {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+                return Err(SmedError::ConfigInvalid(format!("Environment '{}' must be non-empty and only contain lowercase letters, digits and '-'", name)));
+            }
+
+            output::record("environment", name);
+        }
+
+        Ok(Environment { name })
+    }
+
+    pub fn terraform_directory(&self, args: &ArgMatches) -> String {
+        let directory = args.get_one::<String>("terraform-directory").unwrap();
+
+        match &self.name {
+            Some(name) => Path::new(directory).join(name).display().to_string(),
+            None => directory.clone(),
+        }
+    }
+
+    pub fn config(&self, args: &ArgMatches) -> Result<Config, Box<dyn std::error::Error>> {
+        let env_path = args.get_one::<String>("env-path").unwrap();
+
+        match &self.name {
+            Some(name) => Config::from_env_files(&[&format!("{}.{}", env_path, name), env_path]),
+            None => Config::from_env(env_path),
+        }
+    }
+
+    pub fn spec(&self, args: &ArgMatches) -> Result<ClusterSpec, Box<dyn std::error::Error>> {
+        let terraform_directory = self.terraform_directory(args);
+
+        let mut spec = ClusterSpec::load(args.get_one::<String>("spec").unwrap(), self.name.as_deref(), init::defaults(&terraform_directory)?)?;
+
+        spec.ssh.known_hosts_path.get_or_insert_with(|| state::path(&terraform_directory, "known_hosts").display().to_string());
+
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::build_cli;
+    use std::fs;
+
+    #[test]
+    fn test_environment_isolates_state_and_overlays_spec_and_env_file() {
+        let directory = std::env::temp_dir().join(format!("smed-environment-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        fs::write(directory.join("smed.yaml"), "name: shop\nnodes:\n  worker:\n    count: 2\n    instance_type: t3.large\n").unwrap();
+        fs::write(directory.join("smed.staging.yaml"), "region: us-east-2\nnodes:\n  worker:\n    count: 1\n").unwrap();
+        fs::write(directory.join(".env"), "AZURE_SUBSCRIPTION_ID=shared\nAZURE_TENANT_ID=shared\n").unwrap();
+        fs::write(directory.join(".env.staging"), "AZURE_SUBSCRIPTION_ID=staging\n").unwrap();
+
+        let path = |file: &str| directory.join(file).display().to_string();
+        let args = build_cli().get_matches_from([
+            "smed", "deploy", "--env", "staging",
+            "-t", &path("terraform"),
+            "-s", &path("smed.yaml"),
+            "-e", &path(".env"),
+        ]);
+        let args = args.subcommand_matches("deploy").unwrap();
+
+        let environment = Environment::from_args(args).unwrap();
+
+        assert_eq!(environment.terraform_directory(args), path("terraform/staging"));
+
+        let spec = environment.spec(args).unwrap();
+        assert_eq!(spec.name, "shop-staging");
+        assert_eq!(spec.region, "us-east-2");
+        assert_eq!(spec.nodes.worker.count, 1);
+        assert_eq!(spec.nodes.worker.instance_type.as_deref(), Some("t3.large"));
+        assert_eq!(spec.ssh.known_hosts_path, Some(path("terraform/staging/.smed/known_hosts")));
+
+        let config = environment.config(args).unwrap();
+        assert_eq!(config.azure_subscription_id.as_deref(), Some("staging"));
+        assert_eq!(config.azure_tenant_id.as_deref(), Some("shared"));
+
+        let invalid = build_cli().get_matches_from(["smed", "status", "--env", "Prod"]);
+        assert!(Environment::from_args(invalid.subcommand_matches("status").unwrap()).is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,58 +1,140 @@
 use clap::ArgMatches;
+use std::fs;
 use std::str::FromStr;
+use std::sync::Arc;
+
+use serde_yaml::{Mapping, Value};
 
 use crate::cmd::terraform::TerraformClient;
 use crate::cmd::cloud_provider;
-use crate::config::Config;
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::environment::Environment;
+use crate::cmd::state;
+use crate::error::SmedError;
+use crate::output;
+
 
-pub struct InitCommand { }
+pub struct InitCommand {
+    runner: Arc<dyn CommandRunner>,
+}
 
 impl InitCommand {
 
-    pub fn new() -> InitCommand {
-        return InitCommand { }
+    pub fn new(runner: Arc<dyn CommandRunner>) -> InitCommand {
+        return InitCommand { runner }
     }
 
     pub fn execute(&self, args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-        let env_path = args.get_one::<String>("env-path").unwrap();
+        let environment = Environment::from_args(args)?;
 
-        let config = Config::from_env(env_path).unwrap();
+        let config = environment.config(args)?;
 
-        let terraform_directory = args.get_one::<String>("terraform-directory").unwrap();   
-
-        TerraformClient::check()?;
-        TerraformClient::init(terraform_directory)?;
+        let terraform_directory = &environment.terraform_directory(args);
 
         let provider = args.get_one::<String>("provider").unwrap().to_lowercase();
         let region = args.get_one::<String>("region").unwrap().to_lowercase();
 
-        let parsed_provider = cloud_provider::CloudProvider::from_str(&provider).unwrap();
-        let parsed_region = cloud_provider::CloudProviderRegion::from_str(&region).unwrap();
+        let parsed_provider = cloud_provider::CloudProvider::from_str(&provider).map_err(SmedError::ConfigInvalid)?;
+        let parsed_region = cloud_provider::CloudProviderRegion::from_str(&region).map_err(SmedError::ConfigInvalid)?;
+
+        let terraform = TerraformClient::new(self.runner.clone());
 
-        cloud_provider::auth(cloud_provider::CloudProviderAuthParams::new(parsed_provider, parsed_region), &config)?;
+        terraform.check()?;
+        terraform.init(terraform_directory)?;
+
+        output::record("provider", parsed_provider.to_string());
+        output::record("region", parsed_region.to_string());
+
+        cloud_provider::auth(self.runner.as_ref(), cloud_provider::CloudProviderAuthParams::new(parsed_provider, parsed_region), &config)?;
+
+        let mut defaults = Mapping::new();
+        defaults.insert(Value::from("provider"), Value::from(provider));
+        defaults.insert(Value::from("region"), Value::from(region));
+        save_defaults(terraform_directory, &defaults)?;
 
         Ok(())
     }
-} 
+}
+
+// What `smed init` was run with in this terraform directory, so the commands
+// after it use the same provider and region unless the spec says otherwise.
+pub fn defaults(terraform_directory: &str) -> Result<Mapping, Box<dyn std::error::Error>> {
+    let path = state::path(terraform_directory, state::INIT_DEFAULTS);
+
+    if !path.exists() {
+        return Ok(Mapping::new());
+    }
+
+    let contents = fs::read_to_string(&path)?;
+
+    serde_yaml::from_str(&contents).map_err(|e| SmedError::ConfigInvalid(format!("Invalid {}: {}", path.display(), e)).into())
+}
+
+fn save_defaults(terraform_directory: &str, defaults: &Mapping) -> Result<(), Box<dyn std::error::Error>> {
+    state::write_private(&state::path(terraform_directory, state::INIT_DEFAULTS), &serde_yaml::to_string(defaults)?)
+}
     
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::cli::build_cli;
+    use crate::cmd::cloud_provider::CloudProvider;
+    use crate::cmd::runner::ReplayRunner;
+    use crate::config::ClusterSpec;
+
     #[test]
     fn test_init() {
-        // Create a test config with dummy values
-        let config = Config {
-            aws_access_key: "test_access_key".to_string(),
-            aws_secret_key: "test_secret_key".to_string(),
-        };
-        let init_command = InitCommand::new(&config);
-        
-        assert_eq!(init_command.config.aws_access_key, "test_access_key");
-        assert_eq!(init_command.config.aws_secret_key, "test_secret_key");
-        
-        // Note: To test the execute method, you would need to create mock ArgMatches
-        // with the required "provider" and "region" arguments
-    }   
+        let directory = std::env::temp_dir().join(format!("smed-init-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let env_path = directory.join(".env");
+        fs::write(&env_path, "AWS_ACCESS_KEY_ID=test_access_key\nAWS_SECRET_ACCESS_KEY=test_secret_key\n").unwrap();
+
+        let terraform_directory = directory.join("terraform");
+        let args = build_cli().get_matches_from([
+            "smed", "init",
+            "-t", terraform_directory.to_str().unwrap(),
+            "-e", env_path.to_str().unwrap(),
+            "-p", "aws",
+            "-r", "us-east-1",
+        ]);
+
+        let replay = ReplayRunner::from_json(include_str!("../../tests/fixtures/init_aws.json")).unwrap();
+        let init_command = InitCommand::new(Arc::new(replay.clone()));
+
+        init_command.execute(args.subcommand_matches("init").unwrap()).unwrap();
+
+        assert!(replay.unused().is_empty());
+        assert!(terraform_directory.is_dir());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_init_choices_are_the_spec_defaults() {
+        let directory = std::env::temp_dir().join(format!("smed-init-defaults-{}", std::process::id()));
+        let terraform_directory = directory.join("terraform").display().to_string();
+
+        let mut chosen = Mapping::new();
+        chosen.insert(Value::from("provider"), Value::from("gcp"));
+        chosen.insert(Value::from("region"), Value::from("us-east-2"));
+        save_defaults(&terraform_directory, &chosen).unwrap();
+
+        let spec_path = directory.join("smed.yaml");
+        fs::write(&spec_path, "name: shop\n").unwrap();
+
+        let spec = ClusterSpec::load(spec_path.to_str().unwrap(), None, defaults(&terraform_directory).unwrap()).unwrap();
+        assert_eq!(spec.provider, CloudProvider::GCP);
+        assert_eq!(spec.region().unwrap(), "us-east4");
+
+        fs::write(&spec_path, "name: shop\nprovider: azure\nregion: us-east-1\n").unwrap();
+
+        let spec = ClusterSpec::load(spec_path.to_str().unwrap(), None, defaults(&terraform_directory).unwrap()).unwrap();
+        assert_eq!(spec.provider, CloudProvider::AZURE);
+        assert_eq!(spec.region().unwrap(), "eastus");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
\ No newline at end of file
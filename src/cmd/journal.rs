@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cmd::state;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct JournalEntry {
+    node: String,
+    step: String,
+    hash: String,
+}
+
+// Completed deploy steps, persisted after each one so a failed deploy can be
+// resumed. A step is only skipped when its command is unchanged; the deploy
+// runs every step after one that changed, like a new config file or token.
+pub struct Journal {
+    path: Option<PathBuf>,
+    entries: Mutex<Vec<JournalEntry>>,
+}
+
+impl Journal {
+    pub fn load(terraform_directory: &str) -> Result<Journal, Box<dyn std::error::Error>> {
+        let path = state::path(terraform_directory, "journal.json");
+
+        let entries = if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid deploy journal {}: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Journal { path: Some(path), entries: Mutex::new(entries) })
+    }
+
+    pub fn in_memory() -> Journal {
+        Journal { path: None, entries: Mutex::new(Vec::new()) }
+    }
+
+    pub fn reset(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.entries.lock().unwrap().clear();
+
+        if let Some(path) = self.path.as_ref().filter(|path| path.exists()) {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_done(&self, node: &str, step: &str, command: &str) -> bool {
+        let entry = Self::entry(node, step, command);
+
+        self.entries.lock().unwrap().contains(&entry)
+    }
+
+    // Whether the step ran on the node before, whatever its command was.
+    pub fn has_run(&self, node: &str, step: &str) -> bool {
+        self.entries.lock().unwrap().iter().any(|e| e.node == node && e.step == step)
+    }
+
+    pub fn record(&self, node: &str, step: &str, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.retain(|e| !(e.node == node && e.step == step));
+        entries.push(Self::entry(node, step, command));
+
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let temporary_path = path.with_extension("json.tmp");
+            fs::write(&temporary_path, serde_json::to_string_pretty(&*entries)?)?;
+            fs::rename(&temporary_path, path)?;
+        }
+
+        Ok(())
+    }
+
+    fn entry(node: &str, step: &str, command: &str) -> JournalEntry {
+        JournalEntry {
+            node: node.to_string(),
+            step: step.to_string(),
+            hash: format!("{:x}", Sha256::digest(command.as_bytes())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_persists_steps_and_detects_changed_commands() {
+        let directory = std::env::temp_dir().join(format!("smed-journal-{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+
+        let journal = Journal::load(directory).unwrap();
+        journal.record("etcd 1.1.1.1", "Install RKE2 server", "curl | sh").unwrap();
+
+        let reloaded = Journal::load(directory).unwrap();
+        assert!(reloaded.is_done("etcd 1.1.1.1", "Install RKE2 server", "curl | sh"));
+        assert!(!reloaded.is_done("etcd 1.1.1.1", "Install RKE2 server", "curl | sh -s -- --version"));
+        assert!(!reloaded.is_done("etcd 2.2.2.2", "Install RKE2 server", "curl | sh"));
+        assert!(reloaded.has_run("etcd 1.1.1.1", "Install RKE2 server"));
+        assert!(!reloaded.has_run("etcd 1.1.1.1", "Start RKE2 service"));
+
+        reloaded.reset().unwrap();
+        assert!(!Journal::load(directory).unwrap().is_done("etcd 1.1.1.1", "Install RKE2 server", "curl | sh"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
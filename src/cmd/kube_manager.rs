@@ -1,110 +1,729 @@
-use std::process::Command;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use crate::cmd::terraform::TerraformOutput;
+use serde::Serialize;
+use serde_json::json;
 
-pub struct KubeManager { }
+use tokio::sync::Semaphore;
+
+use crate::cmd::airgap::{Artifacts, Upload};
+use crate::cmd::journal::Journal;
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::ssh::{CommandOutput, SshTransport};
+use crate::cmd::state;
+use crate::cmd::terraform::{TerraformOutput, TerraformValue};
+use crate::config::{RancherSettings, Rke2Settings, SshSettings};
+use crate::error::SmedError;
+use crate::output::{self, debug, error, info, success};
+
+const DEFAULT_CONCURRENCY: usize = 5;
+
+const KUBECTL: &str = "sudo /var/lib/rancher/rke2/bin/kubectl --kubeconfig /etc/rancher/rke2/rke2.yaml";
+
+const HELM: &str = "sudo helm --kubeconfig /etc/rancher/rke2/rke2.yaml";
+
+// The tarball install puts rke2 in /usr/local/bin, the RPM one in /opt/rke2/bin
+// Relative to the SSH user's home, written with owner-only permissions
+const BOOTSTRAP_PASSWORD_FILE: &str = ".smed/rancher-password";
+
+const RKE2_VERSION: &str = "PATH=$PATH:/usr/local/bin:/opt/rke2/bin rke2 --version";
+
+// The version the node's running kubelet reports to the API server, asked with
+// the kubelet's own credentials since agents have no admin kubeconfig
+const KUBELET_VERSION: &str = "sudo /var/lib/rancher/rke2/bin/kubectl --kubeconfig /var/lib/rancher/rke2/agent/kubelet.kubeconfig get node \"$(hostname)\" -o jsonpath={.status.nodeInfo.kubeletVersion}";
+
+pub struct KubeManager {
+    transport: Box<dyn SshTransport>,
+    semaphore: Arc<Semaphore>,
+    journal: Arc<Journal>,
+    bootstrap_password: String,
+    rke2: Rke2Install,
+    rancher: RancherSettings,
+    reports: Mutex<Vec<NodeReport>>,
+}
 
 struct SshCommand {
     command: String,
     description: String
 }
 
+struct NodeTask {
+    node: String,
+    ip: String,
+    // Copied to the node before its first command
+    uploads: Vec<Upload>,
+    commands: Vec<SshCommand>,
+    secret_files: Vec<SecretFile>,
+}
+
+// A secret written to a private file on the node right before the step that
+// reads it runs, since command lines show in `ps` to every user of the node.
+// The step deletes the file once done.
+struct SecretFile {
+    step: String,
+    remote: String,
+    contents: String,
+}
+
+// Where nodes get RKE2 from: get.rke2.io, pinned by the spec's rke2 settings,
+// or with artifacts the files uploaded from the machine running smed.
+#[derive(Debug, Clone, Default)]
+struct Rke2Install {
+    settings: Rke2Settings,
+    artifacts: Option<Artifacts>,
+}
+
+impl Rke2Install {
+    fn uploads(&self) -> Vec<Upload> {
+        self.artifacts.as_ref().map(|artifacts| artifacts.uploads.clone()).unwrap_or_default()
+    }
+}
+
+pub struct ClusterNode {
+    pub role: &'static str,
+    pub name: String,
+    pub ip: String,
+}
+
+impl ClusterNode {
+    pub fn service(&self) -> &'static str {
+        if self.role == "Worker" { "rke2-agent" } else { "rke2-server" }
+    }
+
+    fn install_type(&self) -> &'static str {
+        if self.role == "Worker" { "agent" } else { "server" }
+    }
+}
+
+// What happened on a node during `setup_cluster` or `upgrade_node`, for
+// `--output json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeReport {
+    pub role: String,
+    pub node: String,
+    pub ip: String,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub steps: Vec<StepReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    pub description: String,
+    pub status: &'static str,
+    pub duration_ms: u64,
+}
+
+pub struct NodeHealth {
+    pub hostname: String,
+    pub service_state: String,
+    pub version: String,
+}
+
 impl KubeManager {
-    pub fn setup_rancher_cluster(ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn new(runner: &dyn CommandRunner, ssh: &SshSettings) -> KubeManager {
+        Self::with_transport(runner.transport(ssh))
+    }
 
-        println!("\n");
-        println!("\x1b[36m🔧 Setting up Rancher cluster...\x1b[0m");
+    pub fn with_transport(transport: Box<dyn SshTransport>) -> KubeManager {
+        KubeManager { transport, semaphore: Arc::new(Semaphore::new(DEFAULT_CONCURRENCY)), journal: Arc::new(Journal::in_memory()), bootstrap_password: String::new(), rke2: Rke2Install::default(), rancher: RancherSettings::default(), reports: Mutex::new(Vec::new()) }
+    }
+
+    pub fn with_journal(mut self, journal: Arc<Journal>) -> KubeManager {
+        self.journal = journal;
+        self
+    }
 
+    pub fn with_secret(self, secret: &str) -> KubeManager {
+        output::add_secret(secret);
+        self
+    }
+
+    // The initial admin password of the Rancher UI. It reaches the rancher
+    // node in a private file rather than on a command line and is masked in
+    // all output, except where deploy shows it once Rancher is up.
+    pub fn with_bootstrap_password(mut self, password: &str) -> KubeManager {
+        output::add_secret(password);
+        self.bootstrap_password = password.to_string();
+        self
+    }
+
+    pub fn with_rke2(mut self, rke2: &Rke2Settings) -> KubeManager {
+        self.rke2.settings = rke2.clone();
+        self
+    }
+
+    pub fn with_rancher(mut self, rancher: &RancherSettings) -> KubeManager {
+        self.rancher = rancher.clone();
+        self
+    }
+
+    // With artifacts, installs RKE2 from them instead of get.rke2.io, so no
+    // node needs internet access.
+    pub fn with_artifacts(mut self, artifacts: Option<Artifacts>) -> KubeManager {
+        self.rke2.artifacts = artifacts;
+        self
+    }
+
+    pub fn with_concurrency(mut self, limit: usize) -> KubeManager {
+        self.semaphore = Arc::new(Semaphore::new(limit.max(1)));
+        self
+    }
 
-        let rancher_ip = ips.get("rancher_ip").unwrap().to_string();
-        
-        let commands = Self::get_rancher_commands(&rancher_ip, common_token);
+    // Rancher is independent from the downstream cluster, so it is bootstrapped
+    // alongside it. Control plane and workers both join the first etcd server
+    // and only start once every etcd server is up; if etcd fails they are never
+    // started.
+    pub async fn setup_cluster(self: &Arc<Self>, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let downstream = async {
+            self.setup_etcd_cluster(ips, common_token).await?;
 
-        for c in commands {
-            Self::run_ssh_command("ubuntu", &rancher_ip, "~/.ssh/id_rsa", &c.command, &c.description)?;
+            let (control_plane, workers) = tokio::join!(
+                self.setup_control_plane_cluster(ips, common_token),
+                self.setup_worker_nodes(ips, common_token),
+            );
+
+            control_plane.and(workers)
+        };
+
+        let (rancher, downstream) = tokio::join!(self.setup_rancher_cluster(ips, common_token), downstream);
+
+        rancher.and(downstream)
+    }
+
+    pub async fn setup_rancher_cluster(self: &Arc<Self>, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.run_role("Rancher", Self::rancher_tasks(ips, common_token, &self.bootstrap_password, &self.rke2, &self.rancher)?).await
+    }
+
+    // The first etcd server initializes the cluster and the others join it one
+    // at a time, each once the previous one is up, so a member that fails to
+    // join never costs the cluster its quorum.
+    pub async fn setup_etcd_cluster(self: &Arc<Self>, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.run_role_in_order("Etcd", Self::etcd_tasks(ips, common_token, &self.rke2)?).await
+    }
+
+    pub async fn setup_control_plane_cluster(self: &Arc<Self>, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.run_role("Control Plane", Self::control_plane_tasks(ips, common_token, &self.rke2)?).await
+    }
+
+    pub async fn setup_worker_nodes(self: &Arc<Self>, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.run_role("Worker", Self::worker_tasks(ips, common_token, &self.rke2)?).await
+    }
+
+    // Prints the commands every node would run during `setup_cluster`, in
+    // order and with secrets redacted, without connecting to any node.
+    pub fn print_plan(&self, ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let roles = [
+            ("Rancher", Self::rancher_tasks(ips, common_token, &self.bootstrap_password, &self.rke2, &self.rancher)?),
+            ("Etcd", Self::etcd_tasks(ips, common_token, &self.rke2)?),
+            ("Control Plane", Self::control_plane_tasks(ips, common_token, &self.rke2)?),
+            ("Worker", Self::worker_tasks(ips, common_token, &self.rke2)?),
+        ];
+
+        let mut planned = Vec::new();
+
+        for (role, tasks) in roles {
+            info!("📋 {} ({} node(s))", role, tasks.len());
+
+            for task in tasks {
+                let prefix = format!("[{} {}]", task.node, task.ip);
+
+                for upload in &task.uploads {
+                    info!("{}", Self::prefixed(&prefix, &format!("⬆ {} -> {}", upload.local.display(), upload.remote)));
+                }
+
+                for file in &task.secret_files {
+                    info!("{}", Self::prefixed(&prefix, &format!("⬆ ******** -> {} (before {})", file.remote, file.step)));
+                }
+
+                for (i, c) in task.commands.iter().enumerate() {
+                    info!("{}", Self::prefixed(&prefix, &format!("{}. {}", i + 1, c.description)));
+                    info!("{}", Self::prefixed(&prefix, &format!("👉 {}", self.redact(&c.command))));
+                }
+
+                let steps: Vec<_> = task.commands.iter()
+                    .map(|c| json!({ "description": c.description, "command": self.redact(&c.command) }))
+                    .collect();
+                let uploads: Vec<_> = task.uploads.iter().map(|upload| &upload.remote).collect();
+                planned.push(json!({ "role": role, "node": task.node, "ip": task.ip, "uploads": uploads, "steps": steps }));
+            }
         }
 
+        output::record("commands", planned);
+
         Ok(())
     }
 
-    pub fn setup_etcd_cluster(ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
-        println!("\n");
-        println!("\x1b[36m🔧 Setting up Etcd...\x1b[0m");
-        
-        let etcd_public_ip = ips.get("etcd_public_ip").unwrap().to_string();
+    fn rancher_tasks(ips: &TerraformOutput, common_token: &str, bootstrap_password: &str, rke2: &Rke2Install, rancher: &RancherSettings) -> Result<Vec<NodeTask>, SmedError> {
+        let rancher_ip = output_ip(ips, "rancher_ip")?;
+
+        let mut commands = Self::get_rancher_commands(&rancher_ip, common_token, rke2);
 
-        let commands = Self::get_etcd_commands(&etcd_public_ip, common_token);
+        let mut secret_files = Vec::new();
 
-        for c in commands {
-            Self::run_ssh_command("ubuntu", &etcd_public_ip, "~/.ssh/id_rsa", &c.command, &c.description)?;
+        // The Rancher charts and images come from the internet
+        if rke2.artifacts.is_none() {
+            commands.extend(Self::get_rancher_ui_commands(&rancher_ip, rancher));
+            secret_files.push(SecretFile { step: "Install Rancher".to_string(), remote: BOOTSTRAP_PASSWORD_FILE.to_string(), contents: bootstrap_password.to_string() });
         }
 
-        Ok(())
+        Ok(vec![NodeTask { node: "rancher".to_string(), ip: rancher_ip, uploads: rke2.uploads(), commands, secret_files }])
+    }
+
+    fn etcd_tasks(ips: &TerraformOutput, common_token: &str, rke2: &Rke2Install) -> Result<Vec<NodeTask>, SmedError> {
+        let etcd_public_ips = server_ips(ips, "etcd_public_ips", "etcd_public_ip")?;
+        let first_private_ip = server_private_ips(ips)?.remove(0);
+
+        Ok(etcd_public_ips.into_iter().enumerate().map(|(i, etcd_public_ip)| {
+            let server = (i > 0).then_some(first_private_ip.as_str());
+
+            NodeTask {
+                node: server_name("etcd", i),
+                uploads: rke2.uploads(),
+                commands: Self::get_etcd_commands(&etcd_public_ip, server, common_token, rke2),
+                secret_files: Vec::new(),
+                ip: etcd_public_ip,
+            }
+        }).collect())
+    }
+
+    fn control_plane_tasks(ips: &TerraformOutput, common_token: &str, rke2: &Rke2Install) -> Result<Vec<NodeTask>, SmedError> {
+        let control_plane_ips = server_ips(ips, "control_plane_ips", "control_plane_ip")?;
+        let etcd_private_ips = server_private_ips(ips)?;
+
+        Ok(control_plane_ips.into_iter().enumerate().map(|(i, control_plane_ip)| NodeTask {
+            node: server_name("control-plane", i),
+            uploads: rke2.uploads(),
+            commands: Self::get_control_plane_commands(&control_plane_ip, registration_ip(&etcd_private_ips, i), common_token, rke2),
+            secret_files: Vec::new(),
+            ip: control_plane_ip,
+        }).collect())
+    }
+
+    fn worker_tasks(ips: &TerraformOutput, common_token: &str, rke2: &Rke2Install) -> Result<Vec<NodeTask>, SmedError> {
+        let worker_ips = output_ips(ips, "worker_ips")?;
+        let etcd_private_ips = server_private_ips(ips)?;
+
+        Ok(worker_ips.iter().enumerate().map(|(i, worker_ip)| NodeTask {
+            node: format!("worker-{}", i),
+            ip: worker_ip.clone(),
+            uploads: rke2.uploads(),
+            commands: Self::get_worker_commands(registration_ip(&etcd_private_ips, i), common_token, rke2),
+            secret_files: Vec::new(),
+        }).collect())
     }
 
-    pub fn setup_control_plane_cluster(ips: &TerraformOutput, common_token: &str) -> Result<(), Box<dyn std::error::Error>> {
-        println!("\n");
-        println!("\x1b[36m🔧 Setting up Control Plane...\x1b[0m");
-     
-        let control_plane_ip = ips.get("control_plane_ip").unwrap().to_string();
-        let etcd_private_ip = ips.get("etcd_private_ip").unwrap().to_string();
+    // Every node of a deployed cluster, read from the Terraform outputs, in the
+    // order they are bootstrapped.
+    pub fn nodes(ips: &TerraformOutput) -> Result<Vec<ClusterNode>, SmedError> {
+        let mut nodes = vec![
+            ClusterNode { role: "Rancher", name: "rancher".to_string(), ip: output_ip(ips, "rancher_ip")? },
+        ];
+
+        nodes.extend(server_ips(ips, "etcd_public_ips", "etcd_public_ip")?.into_iter().enumerate().map(|(i, ip)| ClusterNode {
+            role: "Etcd",
+            name: server_name("etcd", i),
+            ip,
+        }));
+
+        nodes.extend(server_ips(ips, "control_plane_ips", "control_plane_ip")?.into_iter().enumerate().map(|(i, ip)| ClusterNode {
+            role: "Control Plane",
+            name: server_name("control-plane", i),
+            ip,
+        }));
+
+        nodes.extend(output_ips(ips, "worker_ips")?.iter().enumerate().map(|(i, ip)| ClusterNode {
+            role: "Worker",
+            name: format!("worker-{}", i),
+            ip: ip.clone(),
+        }));
+
+        Ok(nodes)
+    }
 
-        let commands = Self::get_control_plane_commands(&control_plane_ip, &etcd_private_ip, common_token);
+    // Reads the node's hostname, which RKE2 uses as the Kubernetes node name,
+    // the state of its RKE2 service and the installed RKE2 version.
+    pub fn node_health(&self, ip: &str, service: &str) -> Result<NodeHealth, Box<dyn std::error::Error>> {
+        let command = format!("hostname; systemctl is-active {}; {} 2>/dev/null | head -n1", service, RKE2_VERSION);
 
-        for c in commands {
-            Self::run_ssh_command("ubuntu", &control_plane_ip, "~/.ssh/id_rsa", &c.command, &c.description)?;
+        let output = self.transport.exec(ip, &command)?;
+        let mut lines = output.stdout.lines().map(str::trim);
+
+        Ok(NodeHealth {
+            hostname: lines.next().unwrap_or_default().to_string(),
+            service_state: lines.next().filter(|state| !state.is_empty()).unwrap_or("unknown").to_string(),
+            // `rke2 version v1.30.4+rke2r1 (go1.22.5)`
+            version: lines.next().and_then(|line| line.split_whitespace().nth(2)).unwrap_or("-").to_string(),
+        })
+    }
+
+    // Asks the API server on `server_ip` which nodes are Ready, by node name.
+    pub fn node_readiness(&self, server_ip: &str) -> Result<HashMap<String, bool>, Box<dyn std::error::Error>> {
+        let output = self.transport.exec(server_ip, &format!("{} get nodes -o json", KUBECTL))?;
+
+        if !output.success() {
+            return Err(SmedError::SshStepFailed(format!("Failed to list nodes on {}: {}", server_ip, output.stderr.trim())).into());
         }
 
-        Ok(())
+        Self::parse_node_readiness(&output.stdout)
     }
 
+    fn parse_node_readiness(json: &str) -> Result<HashMap<String, bool>, Box<dyn std::error::Error>> {
+        let nodes: serde_json::Value = serde_json::from_str(json)?;
 
-    fn expand_tilde(path: &str) -> String {
-        if path.starts_with("~") {
-            let home = std::env::var("HOME").unwrap_or_default();
-            path.replacen("~", &home, 1)
+        Ok(nodes["items"].as_array().into_iter().flatten().map(|node| {
+            let ready = node["status"]["conditions"].as_array().into_iter().flatten()
+                .any(|condition| condition["type"] == "Ready" && condition["status"] == "True");
+
+            (node["metadata"]["name"].as_str().unwrap_or_default().to_string(), ready)
+        }).collect())
+    }
+
+    pub fn fetch_kubeconfig(&self, server_ip: &str) -> Result<String, Box<dyn std::error::Error>> {
+        info!("📥 Downloading kubeconfig from {}...", server_ip);
+
+        let output = self.transport.exec(server_ip, "sudo cat /etc/rancher/rke2/rke2.yaml")?;
+
+        if output.success() {
+            Ok(output.stdout)
+        } else {
+            Err(SmedError::SshStepFailed(format!("Failed to read /etc/rancher/rke2/rke2.yaml on {}: {}", server_ip, output.stderr.trim())).into())
+        }
+    }
+
+    async fn run_role(self: &Arc<Self>, role: &str, tasks: Vec<NodeTask>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("🔧 Setting up {} ({} node(s))...", role, tasks.len());
+
+        let handles: Vec<_> = tasks.into_iter().map(|task| self.spawn_node(role, task)).collect();
+
+        let mut results = Vec::new();
+        for (label, handle) in handles {
+            let result = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+            results.push((label, result));
+        }
+
+        Self::summarize(role, &results, &[])
+    }
+
+    // Like `run_role`, but each node only starts once the previous one is up,
+    // and none start after one fails.
+    async fn run_role_in_order(self: &Arc<Self>, role: &str, tasks: Vec<NodeTask>) -> Result<(), Box<dyn std::error::Error>> {
+        info!("🔧 Setting up {} ({} node(s), one at a time)...", role, tasks.len());
+
+        let mut results: Vec<(String, Result<(), String>)> = Vec::new();
+        let mut not_started = Vec::new();
+
+        for task in tasks {
+            if results.iter().any(|(_, result)| result.is_err()) {
+                not_started.push(format!("{} {}", task.node, task.ip));
+                continue;
+            }
+
+            let (label, handle) = self.spawn_node(role, task);
+            let result = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+            results.push((label, result));
+        }
+
+        Self::summarize(role, &results, &not_started)
+    }
+
+    fn spawn_node(self: &Arc<Self>, role: &str, task: NodeTask) -> (String, tokio::task::JoinHandle<Result<(), String>>) {
+        let manager = Arc::clone(self);
+        let semaphore = Arc::clone(&self.semaphore);
+        let label = format!("{} {}", task.node, task.ip);
+        let role = role.to_string();
+
+        let handle = tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.map_err(|e| e.to_string())?;
+
+            tokio::task::spawn_blocking(move || manager.run_node(&role, task))
+                .await
+                .map_err(|e| e.to_string())?
+        });
+
+        (label, handle)
+    }
+
+    fn summarize(role: &str, results: &[(String, Result<(), String>)], not_started: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        info!("📋 {} summary:", role);
+        for (label, result) in results {
+            match result {
+                Ok(()) => success!("✔ {}: ready", label),
+                Err(error) => error!("✖ {}: {}", label, error),
+            }
+        }
+
+        for label in not_started {
+            error!("✖ {}: not started, an earlier node failed", label);
+        }
+
+        let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+
+        if failed == 0 {
+            Ok(())
         } else {
-            path.to_string()
+            Err(SmedError::SshStepFailed(format!("{} of {} {} node(s) failed", failed, results.len() + not_started.len(), role.to_lowercase())).into())
         }
     }
-    
+
+    // Steps and outcomes of every node set up so far, in completion order.
+    pub fn reports(&self) -> Vec<NodeReport> {
+        self.reports.lock().unwrap().clone()
+    }
+
+    // Upgrades RKE2 on `node` to `version`, unless its kubelet already runs
+    // it. The node is cordoned and drained through the API server on
+    // `server_ip` first, and only uncordoned once it is Ready again on the new
+    // version. Without `server_ip`, the node is a cluster of its own: it can't
+    // be drained, so it is only waited for.
+    pub fn upgrade_node(&self, node: &ClusterNode, server_ip: Option<&str>, version: &str) -> Result<(), String> {
+        let started = Instant::now();
+        let mut steps = Vec::new();
+
+        let result = self.run_upgrade_steps(node, server_ip, version, &mut steps);
+
+        self.reports.lock().unwrap().push(NodeReport {
+            role: node.role.to_string(),
+            node: node.name.clone(),
+            ip: node.ip.clone(),
+            status: match result {
+                Ok(true) => "current",
+                Ok(false) => "upgraded",
+                Err(_) => "failed",
+            },
+            error: result.as_ref().err().cloned(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            steps,
+        });
+
+        result.map(|_| ())
+    }
+
+    // Whether the node already ran `version`, or the error of the step that failed
+    fn run_upgrade_steps(&self, node: &ClusterNode, server_ip: Option<&str>, version: &str, steps: &mut Vec<StepReport>) -> Result<bool, String> {
+        let prefix = format!("[{} {}]", node.name, node.ip);
+
+        let started = Instant::now();
+
+        let hostname = self.transport.exec(&node.ip, "hostname")
+            .map_err(|e| e.to_string())
+            .and_then(|output| if output.success() { Ok(output.stdout.trim().to_string()) } else { Err(output.stderr.trim().to_string()) })
+            .map_err(|e| format!("Failed to read the hostname of {}: {}", node.ip, e));
+
+        steps.push(StepReport {
+            description: "Read hostname".to_string(),
+            status: if hostname.is_ok() { "done" } else { "failed" },
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+
+        let hostname = hostname?;
+
+        let started = Instant::now();
+
+        // A node that can't tell is upgraded anyway
+        let running = self.transport.exec(&node.ip, KUBELET_VERSION).ok()
+            .filter(|output| output.success())
+            .map(|output| output.stdout.trim().to_string());
+
+        steps.push(StepReport {
+            description: "Check the running version".to_string(),
+            status: "done",
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+
+        if running.as_deref() == Some(version) {
+            info!("{}", Self::prefixed(&prefix, &format!("⏭ Already on {}", version)));
+            return Ok(true);
+        }
+
+        let uploads = self.rke2.uploads();
+
+        // Before the cordon, so a failed upload leaves the node serving
+        if !uploads.is_empty() {
+            self.run_uploads(&format!("{} {}", node.name, node.ip), &prefix, &node.ip, &uploads, steps)?;
+        }
+
+        for (host, c) in Self::get_upgrade_commands(node, &hostname, server_ip, version, &self.rke2) {
+            let started = Instant::now();
+
+            let result = self.run_ssh_command(&prefix, &host, &c.command, &c.description)
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+
+            steps.push(StepReport {
+                description: c.description,
+                status: if result.is_ok() { "done" } else { "failed" },
+                duration_ms: started.elapsed().as_millis() as u64,
+            });
+
+            result?;
+        }
+
+        Ok(false)
+    }
+
+    fn run_node(&self, role: &str, task: NodeTask) -> Result<(), String> {
+        let started = Instant::now();
+        let mut steps = Vec::new();
+
+        let result = self.run_steps(&task, &mut steps);
+
+        self.reports.lock().unwrap().push(NodeReport {
+            role: role.to_string(),
+            node: task.node,
+            ip: task.ip,
+            status: if result.is_ok() { "ready" } else { "failed" },
+            error: result.as_ref().err().cloned(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            steps,
+        });
+
+        result
+    }
+
+    fn run_steps(&self, task: &NodeTask, steps: &mut Vec<StepReport>) -> Result<(), String> {
+        let node = format!("{} {}", task.node, task.ip);
+        let prefix = format!("[{}]", node);
+
+        if !task.uploads.is_empty() {
+            self.run_uploads(&node, &prefix, &task.ip, &task.uploads, steps)?;
+        }
+
+        // Once a step runs, every step after it runs too: they build on it,
+        // like the service that has to pick up a changed config file
+        let mut replaying = false;
+
+        for c in &task.commands {
+            let started = Instant::now();
+
+            if !replaying && self.journal.is_done(&node, &c.description, &c.command) {
+                info!("{}", Self::prefixed(&prefix, &format!("⏭ Already done: {}", c.description)));
+                steps.push(StepReport { description: c.description.clone(), status: "skipped", duration_ms: 0 });
+                continue;
+            }
+
+            replaying = true;
+
+            let command = if self.journal.has_run(&node, &c.description) { rerun_command(&c.command) } else { c.command.clone() };
+
+            let result = task.secret_files.iter()
+                .filter(|file| file.step == c.description)
+                .try_for_each(|file| self.upload_secret(&task.ip, file))
+                .and_then(|_| self.run_ssh_command(&prefix, &task.ip, &command, &c.description))
+                .and_then(|_| self.journal.record(&node, &c.description, &c.command))
+                .map_err(|e| e.to_string());
+
+            steps.push(StepReport {
+                description: c.description.clone(),
+                status: if result.is_ok() { "done" } else { "failed" },
+                duration_ms: started.elapsed().as_millis() as u64,
+            });
+
+            result?;
+        }
+
+        Ok(())
+    }
+
+    // Uploads the files as one journaled step, replayed when any of them
+    // changes.
+    fn run_uploads(&self, node: &str, prefix: &str, ip: &str, uploads: &[Upload], steps: &mut Vec<StepReport>) -> Result<(), String> {
+        let description = "Upload RKE2 artifacts";
+        let fingerprint: Vec<String> = uploads.iter().map(|upload| format!("{}  {}", upload.sha256, upload.remote)).collect();
+        let fingerprint = fingerprint.join("\n");
+
+        if self.journal.is_done(node, description, &fingerprint) {
+            info!("{}", Self::prefixed(prefix, &format!("⏭ Already done: {}", description)));
+            steps.push(StepReport { description: description.to_string(), status: "skipped", duration_ms: 0 });
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        info!("{}", Self::prefixed(prefix, description));
+
+        let result = uploads.iter()
+            .try_for_each(|upload| {
+                debug!("{}", Self::prefixed(prefix, &format!("⬆ {} -> {}", upload.local.display(), upload.remote)));
+                self.transport.upload(ip, &upload.local, &upload.remote)
+            })
+            .and_then(|_| self.journal.record(node, description, &fingerprint))
+            .map_err(|e| e.to_string());
+
+        match &result {
+            Ok(()) => success!("{}", Self::prefixed(prefix, "✔ Success")),
+            Err(error) => error!("{}", Self::prefixed(prefix, &format!("✖ {}", error))),
+        }
+
+        steps.push(StepReport {
+            description: description.to_string(),
+            status: if result.is_ok() { "done" } else { "failed" },
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+
+        result
+    }
+
     fn run_ssh_command(
-        ssh_user: &str,
+        &self,
+        prefix: &str,
         target_ip: &str,
-        key_path: &str,
         command: &str,
         description: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        println!("\x1b[36m👉 {}\x1b[0m", command);
-        println!("{}", description);
-
-        let ssh_target = format!("{}@{}", ssh_user, target_ip);
-        let key_path = Self::expand_tilde(key_path);
-
-        let status = Command::new("ssh")
-            .arg("-i")
-            .arg(&key_path)
-            .arg("-o")
-            .arg("StrictHostKeyChecking=no")
-            .arg(&ssh_target)
-            .arg(command)
-            .status()?;
-
-        if status.success() {
-            println!("\x1b[32m✔ Success\x1b[0m\n");
-            Ok(())
+    ) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        info!("{}", Self::prefixed(prefix, description));
+        debug!("{}", Self::prefixed(prefix, &format!("👉 {}", self.redact(command))));
+
+        let output = self.transport.exec(target_ip, command)?;
+
+        if !output.stdout.trim().is_empty() {
+            debug!("{}", Self::prefixed(prefix, &self.redact(output.stdout.trim_end())));
+        }
+
+        if output.success() {
+            if !output.stderr.trim().is_empty() {
+                debug!("{}", Self::prefixed(prefix, &self.redact(output.stderr.trim_end())));
+            }
+            success!("{}", Self::prefixed(prefix, "✔ Success"));
+            Ok(output)
         } else {
-            println!("\x1b[31m✖ Failed to run: {}\x1b[0m", command);
-            Err(format!("Failed to run: {}", description).into())
+            error!("{}", Self::prefixed(prefix, &format!("✖ Failed to run: {} (exit code {})", self.redact(command), output.exit_code)));
+            if !output.stderr.trim().is_empty() {
+                error!("{}", Self::prefixed(prefix, &self.redact(output.stderr.trim_end())));
+            }
+            Err(format!("Failed to run: {} (exit code {})", description, output.exit_code).into())
         }
     }
 
-    fn get_rancher_commands(rancher_ip: &str, common_token: &str) -> Vec<SshCommand> {
-        return vec![
-            SshCommand {
-                command: "sudo sh -c 'curl -sfL https://get.rke2.io | INSTALL_RKE2_TYPE=\"server\" sh'".to_string(),
-                description: "Install RKE2".to_string(),
-            },
+    fn redact(&self, text: &str) -> String {
+        output::redact(text)
+    }
+
+    // Goes through a local owner-only file, as the transport uploads files
+    fn upload_secret(&self, ip: &str, file: &SecretFile) -> Result<(), Box<dyn std::error::Error>> {
+        let local = std::env::temp_dir().join(format!("smed-secret-{}-{}", std::process::id(), file.remote.replace('/', "-")));
+        state::write_private(&local, &file.contents)?;
+
+        let uploaded = self.transport.upload(ip, &local, &file.remote);
+        fs::remove_file(&local)?;
+
+        uploaded
+    }
+
+    fn prefixed(prefix: &str, text: &str) -> String {
+        text.lines()
+            .map(|line| format!("{} {}", prefix, line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn get_rancher_commands(rancher_ip: &str, common_token: &str, rke2: &Rke2Install) -> Vec<SshCommand> {
+        let mut commands = Self::get_install_commands("server", "Install RKE2", rke2);
+
+        commands.extend([
             SshCommand {
                 command: "sudo mkdir -p /etc/rancher/rke2".to_string(),
                 description: "Create RKE2 directory".to_string(),
@@ -128,35 +747,69 @@ EOF", common_token, rancher_ip, rancher_ip).to_string(),
                 description: "Start RKE2 service".to_string(),
             },
             SshCommand {
-                command: "sudo ln -s /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
+                command: "sudo ln -sf /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
                 description: "Create symlink for kubectl".to_string(),
             },
-            SshCommand {
-                command: "export KUBECONFIG=/etc/rancher/rke2/rke2.yaml".to_string(),
-                description: "Set KUBECONFIG".to_string(),
-            },
-        ];
+        ]);
+
+        commands.extend(Self::get_verify_version_commands(&rke2.settings));
+        commands
     }
 
-    fn get_etcd_commands(etcd_public_ip: &str, common_token: &str) -> Vec<SshCommand> {
-        return vec![
+    // Installs the Rancher server with its Helm chart, behind cert-manager's
+    // self-signed certificate for the sslip.io name already in the node's tls-san.
+    // Helm comes from its release archive, checked against the published
+    // checksum, and both charts are pinned to the versions in `rancher`. The
+    // admin password is read from BOOTSTRAP_PASSWORD_FILE, removed whatever
+    // the outcome.
+    fn get_rancher_ui_commands(rancher_ip: &str, rancher: &RancherSettings) -> Vec<SshCommand> {
+        let helm_archive = format!("helm-{}-linux-amd64.tar.gz", rancher.helm_version);
+
+        vec![
+            SshCommand {
+                command: format!("cd /tmp && curl -fsSLO https://get.helm.sh/{0} && curl -fsSL https://get.helm.sh/{0}.sha256sum | sha256sum -c - && sudo tar -xzf {0} -C /usr/local/bin --strip-components=1 linux-amd64/helm && rm {0}", helm_archive),
+                description: "Install Helm".to_string(),
+            },
+            SshCommand {
+                command: format!("{} wait --for=condition=Ready node --all --timeout=300s", KUBECTL),
+                description: "Wait for the Rancher node to be ready".to_string(),
+            },
+            SshCommand {
+                command: format!("sudo helm repo add jetstack https://charts.jetstack.io --force-update && {} upgrade --install cert-manager jetstack/cert-manager --version {} --namespace cert-manager --create-namespace --set crds.enabled=true --wait --timeout 10m", HELM, rancher.cert_manager_version),
+                description: "Install cert-manager".to_string(),
+            },
+            SshCommand {
+                command: format!("sudo helm repo add rancher-stable https://releases.rancher.com/server-charts/stable --force-update && {0} upgrade --install rancher rancher-stable/rancher --version {1} --namespace cattle-system --create-namespace --set hostname={3} --set-file bootstrapPassword={2} --set replicas=1 --wait --timeout 10m; status=$?; rm -f {2}; exit $status", HELM, rancher.version, BOOTSTRAP_PASSWORD_FILE, rancher_hostname(rancher_ip)),
+                description: "Install Rancher".to_string(),
+            },
             SshCommand {
-                command: "sudo sh -c 'curl -sfL https://get.rke2.io | INSTALL_RKE2_TYPE=\"server\" sh'".to_string(),                
-                description: "Install RKE2 server".to_string(),
+                command: format!("{} -n cattle-system rollout status deploy/rancher --timeout=600s", KUBECTL),
+                description: "Wait for Rancher to roll out".to_string(),
             },
+        ]
+    }
+
+    // Without `server_private_ip` the node initializes a new etcd cluster,
+    // with it the node joins the cluster of that server.
+    fn get_etcd_commands(etcd_public_ip: &str, server_private_ip: Option<&str>, common_token: &str, rke2: &Rke2Install) -> Vec<SshCommand> {
+        let server = server_private_ip.map(|ip| format!("server: https://{}:9345\n", ip)).unwrap_or_default();
+
+        let mut commands = Self::get_install_commands("server", "Install RKE2 server", rke2);
+
+        commands.extend([
             SshCommand {
                 command: "sudo mkdir -p /etc/rancher/rke2".to_string(),
                 description: "Create RKE2 directory".to_string(),
             },
             SshCommand {
                 command: format!("sudo tee /etc/rancher/rke2/config.yaml > /dev/null <<EOF
-token: {}
+{}token: {}
 tls-san:
     - {}
 node-taint:
     - \"etcd=true:NoExecute\"
 EOF", 
-             common_token, etcd_public_ip).to_string(),
+             server, common_token, etcd_public_ip).to_string(),
                 description: "Create RKE2 config file".to_string(),
             },
             SshCommand {
@@ -168,22 +821,19 @@ EOF",
                 description: "Start RKE2 service".to_string(),
             },
             SshCommand {
-                command: "sudo ln -s /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
+                command: "sudo ln -sf /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
                 description: "Create symlink for kubectl".to_string(),
             },
-            SshCommand {
-                command: "export KUBECONFIG=/etc/rancher/rke2/rke2.yaml".to_string(),
-                description: "Set KUBECONFIG".to_string(),
-            },
-        ];
+        ]);
+
+        commands.extend(Self::get_verify_version_commands(&rke2.settings));
+        commands
     }
 
-    fn get_control_plane_commands(control_plane_ip: &str, etcd_private_ip: &str, common_token: &str) -> Vec<SshCommand> {
-        return vec![
-            SshCommand {
-                command: "sudo sh -c 'curl -sfL https://get.rke2.io | INSTALL_RKE2_TYPE=\"server\" sh'".to_string(),                
-                description: "Install RKE2 server".to_string(),
-            },
+    fn get_control_plane_commands(control_plane_ip: &str, etcd_private_ip: &str, common_token: &str, rke2: &Rke2Install) -> Vec<SshCommand> {
+        let mut commands = Self::get_install_commands("server", "Install RKE2 server", rke2);
+
+        commands.extend([
             SshCommand {
                 command: "sudo mkdir -p /etc/rancher/rke2".to_string(),
                 description: "Create RKE2 directory".to_string(),
@@ -192,6 +842,7 @@ EOF",
                 command: format!("sudo tee /etc/rancher/rke2/config.yaml > /dev/null <<EOF
 server: https://{}:9345
 token: {}
+disable-etcd: true
 tls-san:
     - {}
 node-taint:
@@ -208,14 +859,444 @@ EOF", etcd_private_ip, common_token, control_plane_ip).to_string(),
                 description: "Start RKE2 service".to_string(),
             },
             SshCommand {
-                command: "sudo ln -s /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
+                command: "sudo ln -sf /var/lib/rancher/rke2/bin/kubectl /usr/local/bin/kubectl && sudo chmod +x /usr/local/bin/kubectl".to_string(),
                 description: "Create symlink for kubectl".to_string(),
             },
+        ]);
+
+        commands.extend(Self::get_verify_version_commands(&rke2.settings));
+        commands
+    }
+
+    // Each command with the host it runs on: kubectl runs on the server, the
+    // install and restart on the node being upgraded.
+    fn get_upgrade_commands(node: &ClusterNode, hostname: &str, server_ip: Option<&str>, version: &str, rke2: &Rke2Install) -> Vec<(String, SshCommand)> {
+        let on_server = |command: String, description: &str| (server_ip.unwrap_or(&node.ip).to_string(), SshCommand { command, description: description.to_string() });
+        let on_node = |command: String, description: &str| (node.ip.clone(), SshCommand { command, description: description.to_string() });
+
+        let rke2 = Rke2Install { settings: Rke2Settings { version: Some(version.to_string()), ..Default::default() }, artifacts: rke2.artifacts.clone() };
+        let verify = Self::get_verify_version_commands(&rke2.settings).remove(0);
+
+        let mut commands = Vec::new();
+
+        if server_ip.is_some() {
+            commands.extend([
+                on_server(format!("{} cordon {}", KUBECTL, hostname), "Cordon node"),
+                on_server(format!("{} drain {} --ignore-daemonsets --delete-emptydir-data --force --timeout=300s", KUBECTL, hostname), "Drain node"),
+            ]);
+        }
+
+        commands.extend(Self::get_install_commands(node.install_type(), &format!("Install RKE2 {}", version), &rke2).into_iter().map(|c| (node.ip.clone(), c)));
+
+        commands.extend([
+            on_node(format!("sudo systemctl restart {}", node.service()), "Restart RKE2 service"),
+            on_node(verify.command, &verify.description),
+            // The API server may need the node that just restarted, so retry until it answers
+            on_server(format!("timeout 600 sh -c 'until {} wait --for=condition=Ready node/{} --timeout=10s; do sleep 5; done'", KUBECTL, hostname), "Wait for the node to be Ready"),
+        ]);
+
+        if server_ip.is_some() {
+            commands.push(on_server(format!("{} uncordon {}", KUBECTL, hostname), "Uncordon node"));
+        }
+
+        commands
+    }
+
+    fn get_worker_commands(server_private_ip: &str, common_token: &str, rke2: &Rke2Install) -> Vec<SshCommand> {
+        let mut commands = Self::get_install_commands("agent", "Install RKE2 agent", rke2);
+
+        commands.extend([
             SshCommand {
-                command: "export KUBECONFIG=/etc/rancher/rke2/rke2.yaml".to_string(),
-                description: "Set KUBECONFIG".to_string(),
+                command: "sudo mkdir -p /etc/rancher/rke2".to_string(),
+                description: "Create RKE2 directory".to_string(),
             },
-        ];
+            SshCommand {
+                command: format!("sudo tee /etc/rancher/rke2/config.yaml > /dev/null <<EOF
+server: https://{}:9345
+token: {}
+EOF", server_private_ip, common_token).to_string(),
+                description: "Create RKE2 agent config file".to_string(),
+            },
+            SshCommand {
+                command: "sudo systemctl enable rke2-agent".to_string(),
+                description: "Enable RKE2 agent service".to_string(),
+            },
+            SshCommand {
+                command: "sudo systemctl start rke2-agent".to_string(),
+                description: "Start RKE2 agent service".to_string(),
+            },
+        ]);
+
+        commands.extend(Self::get_verify_version_commands(&rke2.settings));
+        commands
+    }
+
+    // From get.rke2.io, or with artifacts from the uploaded files once they
+    // match the checksums computed where smed runs.
+    fn get_install_commands(install_type: &str, description: &str, rke2: &Rke2Install) -> Vec<SshCommand> {
+        match &rke2.artifacts {
+            Some(artifacts) => vec![
+                SshCommand {
+                    command: artifacts.verify_command(),
+                    description: "Verify RKE2 artifacts".to_string(),
+                },
+                SshCommand {
+                    command: artifacts.install_command(install_type),
+                    description: description.to_string(),
+                },
+            ],
+            None => vec![
+                SshCommand {
+                    command: install_command(install_type, &rke2.settings),
+                    description: description.to_string(),
+                },
+            ],
+        }
+    }
+
+    // Checks the release the node runs once RKE2 has started, as reported by
+    // its kubelet rather than the binary on disk, which a rerun install may have
+    // replaced without a restart. The kubelet reports a new version shortly
+    // after a restart, so it is asked until it matches, for up to 5 minutes.
+    // An exact version is matched as is and a channel like v1.30 by its minor
+    // version; stable and latest can point at any release, so there is nothing
+    // to check.
+    fn get_verify_version_commands(rke2: &Rke2Settings) -> Vec<SshCommand> {
+        let (grep, pinned) = match (&rke2.version, &rke2.channel) {
+            (Some(version), _) => (format!("grep -qxF {}", version), version),
+            (None, Some(channel)) if channel.starts_with('v') => (format!("grep -qF {}.", channel), channel),
+            _ => return Vec::new(),
+        };
+
+        vec![
+            SshCommand {
+                // The running version goes to stderr so a mismatch shows it
+                command: format!("timeout 300 sh -c 'until {0} | {1}; do sleep 5; done' || {{ {0} >&2; false; }}", KUBELET_VERSION, grep),
+                description: format!("Verify the node runs RKE2 {}", pinned),
+            },
+        ]
+    }
+
+}
+
+// A step that ran before on the node: starting the running service again
+// would do nothing, so it is restarted to pick up what changed before it.
+fn rerun_command(command: &str) -> String {
+    command.replace("systemctl start ", "systemctl restart ")
+}
+
+// The get.rke2.io install command for `install_type` (server or agent),
+// pinned to the spec's version or channel when it sets one.
+fn install_command(install_type: &str, rke2: &Rke2Settings) -> String {
+    let mut env = format!("INSTALL_RKE2_TYPE=\"{}\"", install_type);
+
+    if let Some(version) = &rke2.version {
+        env.push_str(&format!(" INSTALL_RKE2_VERSION=\"{}\"", version));
+    }
+
+    if let Some(channel) = &rke2.channel {
+        env.push_str(&format!(" INSTALL_RKE2_CHANNEL=\"{}\"", channel));
+    }
+
+    format!("sudo sh -c 'curl -sfL https://get.rke2.io | {} sh'", env)
+}
+
+// The name the Rancher UI is served on, resolved to `rancher_ip` by sslip.io.
+pub fn rancher_hostname(rancher_ip: &str) -> String {
+    format!("{}.sslip.io", rancher_ip)
+}
+
+// The addresses Kubernetes clients can use, in order of preference: the etcd
+// servers, which also run the API server.
+pub fn api_server_ips(ips: &TerraformOutput) -> Result<Vec<String>, SmedError> {
+    server_ips(ips, "etcd_public_ips", "etcd_public_ip")
+}
+
+// The addresses other nodes register with: the etcd servers, the first one
+// of which initialized the cluster.
+fn server_private_ips(ips: &TerraformOutput) -> Result<Vec<String>, SmedError> {
+    server_ips(ips, "etcd_private_ips", "etcd_private_ip")
+}
+
+// The etcd server the `index`th node of a role registers with, taken in turn
+// so one server being down only keeps some nodes from (re)joining. Once
+// joined, RKE2 reaches every server through its own client-side load balancer.
+fn registration_ip(etcd_private_ips: &[String], index: usize) -> &str {
+    &etcd_private_ips[index % etcd_private_ips.len()]
+}
+
+// The IPs of a role that can have several servers, from the list output
+// `key`. Templates written before etcd and control-plane nodes could be more
+// than one, and the state they left, only have the single-IP `legacy_key`.
+fn server_ips(ips: &TerraformOutput, key: &str, legacy_key: &str) -> Result<Vec<String>, SmedError> {
+    match (ips.get(key), ips.get(legacy_key)) {
+        (Some(TerraformValue::List { value }), _) if !value.is_empty() => Ok(value.clone()),
+        (None, Some(TerraformValue::String { value })) => Ok(vec![value.clone()]),
+        _ => Err(SmedError::OutputMissing(key.to_string())),
+    }
+}
+
+// The first server of a role keeps the role's name and the others are
+// numbered from 1, the names the templates give the machines.
+fn server_name(role: &str, index: usize) -> String {
+    if index == 0 { role.to_string() } else { format!("{}-{}", role, index) }
+}
+
+pub fn output_ip(ips: &TerraformOutput, key: &str) -> Result<String, SmedError> {
+    match ips.get(key) {
+        Some(TerraformValue::String { value }) => Ok(value.clone()),
+        _ => Err(SmedError::OutputMissing(key.to_string())),
+    }
+}
+
+fn output_ips<'a>(ips: &'a TerraformOutput, key: &str) -> Result<&'a Vec<String>, SmedError> {
+    match ips.get(key) {
+        Some(TerraformValue::List { value }) => Ok(value),
+        _ => Err(SmedError::OutputMissing(key.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    struct FakeTransport {
+        failing_host: &'static str,
+        executed: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl SshTransport for FakeTransport {
+        fn exec(&self, host: &str, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+            self.executed.lock().unwrap().push((host.to_string(), command.to_string()));
+
+            let exit_code = if host == self.failing_host && command.contains("systemctl start") { 1 } else { 0 };
+
+            Ok(CommandOutput { stdout: String::new(), stderr: "boom".to_string(), exit_code })
+        }
+
+        fn upload(&self, host: &str, _local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+            self.executed.lock().unwrap().push((host.to_string(), format!("upload {}", remote)));
+
+            Ok(())
+        }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_setup_worker_nodes_reports_failed_workers() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "10.0.0.2", executed: executed.clone() })));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()] });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(manager.setup_worker_nodes(&ips, "token")).unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of 2 worker node(s) failed");
+
+        let executed = executed.lock().unwrap();
+        assert_eq!(executed.iter().filter(|(host, _)| host == "10.0.0.1").count(), 5);
+        assert!(executed.iter().any(|(host, command)| host == "10.0.0.1" && command.contains("server: https://172.31.0.10:9345")));
+
+        let reports = manager.reports();
+        let failed = reports.iter().find(|report| report.ip == "10.0.0.2").unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(failed.status, "failed");
+        assert_eq!(failed.steps.last().unwrap().status, "failed");
+        assert_eq!(failed.steps.last().unwrap().description, "Start RKE2 agent service");
+    }
+
+    #[test]
+    fn test_steps_after_a_changed_step_run_again_and_restart_the_service() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "", executed: executed.clone() })));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: vec!["10.0.0.1".to_string()] });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(manager.setup_worker_nodes(&ips, "old-token")).unwrap();
+        executed.lock().unwrap().clear();
+
+        runtime.block_on(manager.setup_worker_nodes(&ips, "new-token")).unwrap();
+
+        let executed = executed.lock().unwrap();
+        let commands: Vec<&str> = executed.iter().map(|(_, command)| command.as_str()).collect();
+
+        assert_eq!(commands.len(), 3);
+        assert!(commands[0].contains("token: new-token"));
+        assert_eq!(commands[1], "sudo systemctl enable rke2-agent");
+        assert_eq!(commands[2], "sudo systemctl restart rke2-agent");
+    }
+
+    #[test]
+    fn test_setup_cluster_skips_nodes_that_depend_on_a_failed_etcd() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "1.1.1.2", executed: executed.clone() })));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("rancher_ip".to_string(), TerraformValue::String { value: "1.1.1.1".to_string() });
+        ips.insert("etcd_public_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.2".to_string()] });
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string()] });
+        ips.insert("control_plane_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.3".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.4".to_string()] });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(manager.setup_cluster(&ips, "token")).unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of 1 etcd node(s) failed");
+
+        let executed = executed.lock().unwrap();
+        assert!(executed.iter().any(|(host, _)| host == "1.1.1.1"));
+        assert!(!executed.iter().any(|(host, _)| host == "1.1.1.3" || host == "1.1.1.4"));
+    }
+
+    #[test]
+    fn test_etcd_servers_join_the_first_one_at_a_time() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "1.1.1.3", executed: executed.clone() })));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("etcd_public_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.2".to_string(), "1.1.1.3".to_string(), "1.1.1.4".to_string()] });
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string(), "172.31.0.11".to_string(), "172.31.0.12".to_string()] });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(manager.setup_etcd_cluster(&ips, "token")).unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of 3 etcd node(s) failed");
+
+        let executed = executed.lock().unwrap();
+        let config = |host: &str| executed.iter().find(|(h, command)| h == host && command.contains("config.yaml")).map(|(_, command)| command.clone());
+
+        assert!(!config("1.1.1.2").unwrap().contains("server:"));
+        assert!(config("1.1.1.3").unwrap().contains("server: https://172.31.0.10:9345"));
+        assert!(!executed.iter().any(|(host, _)| host == "1.1.1.4"));
+
+        let reports = manager.reports();
+        assert_eq!(reports.iter().map(|report| report.node.as_str()).collect::<Vec<_>>(), ["etcd", "etcd-1"]);
+    }
+
+    #[test]
+    fn test_nodes_register_with_the_etcd_servers_in_turn() {
+        let mut ips = TerraformOutput::new();
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string(), "172.31.0.11".to_string(), "172.31.0.12".to_string()] });
+        ips.insert("control_plane_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.5".to_string(), "1.1.1.6".to_string(), "1.1.1.7".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: (1..=4).map(|i| format!("10.0.0.{}", i)).collect() });
+
+        let server = |task: &NodeTask| task.commands.iter()
+            .find_map(|c| c.command.lines().find_map(|line| line.strip_prefix("server: ")))
+            .unwrap()
+            .to_string();
+
+        let control_planes = KubeManager::control_plane_tasks(&ips, "token", &Rke2Install::default()).unwrap();
+        assert_eq!(control_planes.iter().map(server).collect::<Vec<_>>(), ["https://172.31.0.10:9345", "https://172.31.0.11:9345", "https://172.31.0.12:9345"]);
+
+        let workers = KubeManager::worker_tasks(&ips, "token", &Rke2Install::default()).unwrap();
+        assert_eq!(workers.iter().map(server).collect::<Vec<_>>(), ["https://172.31.0.10:9345", "https://172.31.0.11:9345", "https://172.31.0.12:9345", "https://172.31.0.10:9345"]);
+    }
+
+    #[test]
+    fn test_nodes_read_single_ip_outputs_of_older_templates() {
+        let mut ips = TerraformOutput::new();
+        ips.insert("rancher_ip".to_string(), TerraformValue::String { value: "1.1.1.1".to_string() });
+        ips.insert("etcd_public_ip".to_string(), TerraformValue::String { value: "1.1.1.2".to_string() });
+        ips.insert("control_plane_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.3".to_string(), "1.1.1.4".to_string(), "1.1.1.5".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: Vec::new() });
+
+        let nodes = KubeManager::nodes(&ips).unwrap();
+
+        assert_eq!(nodes.iter().map(|node| node.name.as_str()).collect::<Vec<_>>(), ["rancher", "etcd", "control-plane", "control-plane-1", "control-plane-2"]);
+        assert_eq!(api_server_ips(&ips).unwrap(), ["1.1.1.2"]);
+    }
+
+    #[test]
+    fn test_secrets_are_redacted_from_printed_commands() {
+        let manager = KubeManager::with_transport(Box::new(FakeTransport { failing_host: "", executed: Arc::new(Mutex::new(Vec::new())) }))
+            .with_secret("s3cr3t");
+
+        let commands = KubeManager::get_worker_commands("172.31.0.10", "s3cr3t", &Rke2Install::default());
+        let printed = manager.redact(&commands[2].command);
+
+        assert!(commands[2].command.contains("token: s3cr3t"));
+        assert!(printed.contains("token: ********"));
+        assert!(!printed.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_rancher_password_reaches_the_node_in_a_file() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "", executed: executed.clone() }))
+            .with_bootstrap_password("rancher-s3cret"));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("rancher_ip".to_string(), TerraformValue::String { value: "1.1.1.1".to_string() });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(manager.setup_rancher_cluster(&ips, "token")).unwrap();
+
+        let executed = executed.lock().unwrap();
+        let position = |needle: &str| executed.iter().position(|(_, command)| command.contains(needle)).unwrap();
+
+        assert!(position("upload .smed/rancher-password") < position("--set-file bootstrapPassword=.smed/rancher-password"));
+        assert!(executed[position("--set-file")].1.contains("rm -f .smed/rancher-password"));
+        assert!(!executed.iter().any(|(_, command)| command.contains("rancher-s3cret")));
+        assert_eq!(output::redact("rancher-s3cret"), "********");
+    }
+
+    #[test]
+    fn test_rke2_version_is_pinned_and_verified() {
+        let rke2 = Rke2Install { settings: Rke2Settings { version: Some("v1.30.4+rke2r1".to_string()), ..Default::default() }, artifacts: None };
+        let commands = KubeManager::get_etcd_commands("1.1.1.2", None, "token", &rke2);
+
+        assert_eq!(commands[0].command, "sudo sh -c 'curl -sfL https://get.rke2.io | INSTALL_RKE2_TYPE=\"server\" INSTALL_RKE2_VERSION=\"v1.30.4+rke2r1\" sh'");
+        assert!(commands.last().unwrap().command.contains("jsonpath={.status.nodeInfo.kubeletVersion} | grep -qxF v1.30.4+rke2r1;"));
+
+        let channel = Rke2Install { settings: Rke2Settings { channel: Some("stable".to_string()), ..Default::default() }, artifacts: None };
+        let commands = KubeManager::get_worker_commands("172.31.0.10", "token", &channel);
+
+        assert!(commands[0].command.contains("INSTALL_RKE2_CHANNEL=\"stable\""));
+        assert_eq!(commands.last().unwrap().description, "Start RKE2 agent service");
+    }
+
+    #[test]
+    fn test_air_gapped_nodes_install_from_the_uploaded_artifacts() {
+        let executed = Arc::new(Mutex::new(Vec::new()));
+        let artifacts = Artifacts { uploads: vec![Upload { local: PathBuf::from("install.sh"), remote: "/var/tmp/smed-rke2-artifacts/install.sh".to_string(), sha256: "abc".to_string() }] };
+        let manager = Arc::new(KubeManager::with_transport(Box::new(FakeTransport { failing_host: "", executed: executed.clone() })).with_artifacts(Some(artifacts)));
+
+        let mut ips = TerraformOutput::new();
+        ips.insert("rancher_ip".to_string(), TerraformValue::String { value: "1.1.1.1".to_string() });
+        ips.insert("etcd_public_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.2".to_string()] });
+        ips.insert("etcd_private_ips".to_string(), TerraformValue::List { value: vec!["172.31.0.10".to_string()] });
+        ips.insert("control_plane_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.3".to_string()] });
+        ips.insert("worker_ips".to_string(), TerraformValue::List { value: vec!["1.1.1.4".to_string()] });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(manager.setup_cluster(&ips, "token")).unwrap();
+
+        let executed = executed.lock().unwrap();
+        let on_worker: Vec<&str> = executed.iter().filter(|(host, _)| host == "1.1.1.4").map(|(_, command)| command.as_str()).collect();
+
+        assert_eq!(on_worker[0], "upload /var/tmp/smed-rke2-artifacts/install.sh");
+        assert_eq!(on_worker[1], "printf '%s\\n' 'abc  /var/tmp/smed-rke2-artifacts/install.sh' | sha256sum --check --quiet -");
+        assert!(on_worker[2].contains("INSTALL_RKE2_ARTIFACT_PATH=/var/tmp/smed-rke2-artifacts INSTALL_RKE2_TYPE=\"agent\""));
+        assert!(!executed.iter().any(|(_, command)| command.contains("curl")));
+    }
+
+    #[test]
+    fn test_parse_node_readiness() {
+        let json = r#"{"items": [
+            {"metadata": {"name": "ip-172-31-0-10"}, "status": {"conditions": [{"type": "MemoryPressure", "status": "False"}, {"type": "Ready", "status": "True"}]}},
+            {"metadata": {"name": "ip-172-31-0-11"}, "status": {"conditions": [{"type": "Ready", "status": "Unknown"}]}}
+        ]}"#;
+
+        let readiness = KubeManager::parse_node_readiness(json).unwrap();
+
+        assert_eq!(readiness.get("ip-172-31-0-10"), Some(&true));
+        assert_eq!(readiness.get("ip-172-31-0-11"), Some(&false));
+        assert_eq!(readiness.len(), 2);
+    }
+}
@@ -0,0 +1,274 @@
+use clap::ArgMatches;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_yaml::{Mapping, Value};
+
+use crate::cmd::cloud_provider;
+use crate::cmd::environment::Environment;
+use crate::cmd::kube_manager::{api_server_ips, KubeManager};
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::ssh::expand_tilde;
+use crate::cmd::state;
+use crate::cmd::terraform::{TerraformClient, TerraformOutput};
+use crate::config::ClusterSpec;
+use crate::error::SmedError;
+use crate::output::{self, success, warning};
+
+const SECTIONS: [&str; 3] = ["clusters", "users", "contexts"];
+
+const MERGED_MARKER: &str = "kubeconfig.merged";
+
+pub fn handle(args: &ArgMatches, runner: Arc<dyn CommandRunner>) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = Environment::from_args(args)?;
+
+    let terraform_directory = &environment.terraform_directory(args);
+
+    let spec = environment.spec(args)?;
+    let config = environment.config(args)?;
+
+    let terraform_env = cloud_provider::terraform_env(&spec.provider, &config)?;
+
+    let terraform = TerraformClient::new(runner.clone());
+
+    terraform.attach(terraform_directory, &spec)?;
+    let output = terraform.output(terraform_directory, &terraform_env)?;
+
+    let merge_into = args.get_flag("merge").then(default_path);
+
+    export(&KubeManager::new(runner.as_ref(), &spec.ssh), &output, &spec, terraform_directory, merge_into.as_deref())
+}
+
+// Downloads the kubeconfig of the downstream cluster from the first server
+// that answers, saves it in the cluster's state directory pointing at that
+// server and optionally merges it into `merge_into` under a context named
+// after the cluster.
+pub fn export(kube_manager: &KubeManager, output: &TerraformOutput, spec: &ClusterSpec, terraform_directory: &str, merge_into: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let (server_ip, raw) = fetch(kube_manager, &api_server_ips(output)?)?;
+    let contents = for_cluster(&raw, &spec.name, &server_ip)?;
+
+    let path = save(terraform_directory, &contents)?;
+    success!("✔ Kubeconfig saved to {}", path.display());
+    output::record("kubeconfig", path.display().to_string());
+
+    if let Some(target) = merge_into {
+        merge(&contents, target)?;
+        state::write_private(&state::path(terraform_directory, MERGED_MARKER), &target.display().to_string())?;
+
+        success!("✔ Kubeconfig merged into {} as context '{}'", target.display(), spec.name);
+        output::record("kubeconfig_merged_into", target.display().to_string());
+    }
+
+    Ok(())
+}
+
+fn fetch(kube_manager: &KubeManager, server_ips: &[String]) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let (last, others) = server_ips.split_last().ok_or_else(|| SmedError::OutputMissing("etcd_public_ips".to_string()))?;
+
+    for server_ip in others {
+        match kube_manager.fetch_kubeconfig(server_ip) {
+            Ok(raw) => return Ok((server_ip.to_string(), raw)),
+            Err(e) => warning!("⚠ {}, trying the next server", e),
+        }
+    }
+
+    Ok((last.to_string(), kube_manager.fetch_kubeconfig(last)?))
+}
+
+// Removes the context smed merged for this cluster, if it merged one.
+pub fn forget(terraform_directory: &str, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let marker = state::path(terraform_directory, MERGED_MARKER);
+
+    if !marker.exists() {
+        return Ok(());
+    }
+
+    let target = PathBuf::from(fs::read_to_string(&marker)?.trim());
+    remove(name, &target)?;
+
+    success!("✔ Removed context '{}' from {}", name, target.display());
+    Ok(())
+}
+
+// Turns the rke2.yaml of a server node into a kubeconfig usable from outside
+// the node: the API server address points at `server_host` instead of
+// 127.0.0.1 and the cluster, user and context are all named `name` instead of
+// RKE2's `default`, so several clusters can live in the same kubeconfig.
+pub fn for_cluster(raw: &str, name: &str, server_host: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut config: Value = serde_yaml::from_str(raw).map_err(|e| format!("Invalid rke2.yaml: {}", e))?;
+
+    for section in SECTIONS {
+        for entry in entries_mut(&mut config, section) {
+            entry["name"] = Value::from(name);
+
+            if let Some(server) = entry["cluster"]["server"].as_str().filter(|_| section == "clusters") {
+                entry["cluster"]["server"] = Value::from(server.replace("127.0.0.1", server_host));
+            }
+
+            if section == "contexts" {
+                entry["context"]["cluster"] = Value::from(name);
+                entry["context"]["user"] = Value::from(name);
+            }
+        }
+    }
+
+    config["current-context"] = Value::from(name);
+
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+pub fn save(terraform_directory: &str, contents: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = state::path(terraform_directory, "kubeconfig.yaml");
+
+    state::write_private(&path, contents)?;
+
+    Ok(path)
+}
+
+pub fn default_path() -> PathBuf {
+    PathBuf::from(expand_tilde("~/.kube/config"))
+}
+
+// Adds the clusters, users and contexts of `contents` to the kubeconfig at
+// `target`, replacing entries with the same name. The target's current
+// context is only set when it has none.
+pub fn merge(contents: &str, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let incoming: Value = serde_yaml::from_str(contents)?;
+    let mut config = load(target)?;
+
+    for section in SECTIONS {
+        let new_entries = incoming[section].as_sequence().cloned().unwrap_or_default();
+        let names: Vec<&Value> = new_entries.iter().map(|entry| &entry["name"]).collect();
+
+        let mut merged: Vec<Value> = config[section].as_sequence().cloned().unwrap_or_default()
+            .into_iter()
+            .filter(|entry| !names.contains(&&entry["name"]))
+            .collect();
+        merged.extend(new_entries.iter().cloned());
+
+        config[section] = Value::Sequence(merged);
+    }
+
+    if config["current-context"].as_str().unwrap_or_default().is_empty() {
+        config["current-context"] = incoming["current-context"].clone();
+    }
+
+    write(target, &config)
+}
+
+// Removes the cluster, user and context called `name` from the kubeconfig at
+// `target`, leaving every other entry untouched.
+pub fn remove(name: &str, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !target.exists() {
+        return Ok(());
+    }
+
+    let mut config = load(target)?;
+
+    for section in SECTIONS {
+        if let Some(entries) = config[section].as_sequence_mut() {
+            entries.retain(|entry| entry["name"].as_str() != Some(name));
+        }
+    }
+
+    if config["current-context"].as_str() == Some(name) {
+        config["current-context"] = Value::from("");
+    }
+
+    write(target, &config)
+}
+
+fn entries_mut<'a>(config: &'a mut Value, section: &str) -> impl Iterator<Item = &'a mut Value> {
+    config.get_mut(section)
+        .and_then(Value::as_sequence_mut)
+        .into_iter()
+        .flatten()
+}
+
+fn load(path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        let mut config = Mapping::new();
+        config.insert(Value::from("apiVersion"), Value::from("v1"));
+        config.insert(Value::from("kind"), Value::from("Config"));
+        return Ok(Value::Mapping(config));
+    }
+
+    let contents = fs::read_to_string(path)?;
+
+    serde_yaml::from_str(&contents).map_err(|e| format!("Invalid kubeconfig {}: {}", path.display(), e).into())
+}
+
+fn write(path: &Path, config: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    state::write_private(path, &serde_yaml::to_string(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::runner::ReplayRunner;
+    use crate::config::SshSettings;
+
+    const RKE2_YAML: &str = "
+apiVersion: v1
+clusters:
+- cluster:
+    certificate-authority-data: Q0E=
+    server: https://127.0.0.1:6443
+  name: default
+contexts:
+- context:
+    cluster: default
+    user: default
+  name: default
+current-context: default
+kind: Config
+users:
+- name: default
+  user:
+    client-certificate-data: Q0VSVA==
+";
+
+    #[test]
+    fn test_kubeconfig_is_rewritten_merged_and_removed() {
+        let contents = for_cluster(RKE2_YAML, "staging", "54.1.2.3").unwrap();
+
+        assert!(contents.contains("server: https://54.1.2.3:6443"));
+        assert!(contents.contains("current-context: staging"));
+        assert!(!contents.contains("default"));
+
+        let target = std::env::temp_dir().join(format!("smed-kubeconfig-{}", std::process::id()));
+        fs::write(&target, "apiVersion: v1\nkind: Config\ncurrent-context: other\ncontexts:\n- name: other\n  context:\n    cluster: other\n    user: other\n").unwrap();
+
+        merge(&contents, &target).unwrap();
+        merge(&contents, &target).unwrap();
+
+        let merged: Value = serde_yaml::from_str(&fs::read_to_string(&target).unwrap()).unwrap();
+        assert_eq!(merged["contexts"].as_sequence().unwrap().len(), 2);
+        assert_eq!(merged["clusters"].as_sequence().unwrap().len(), 1);
+        assert_eq!(merged["current-context"].as_str(), Some("other"));
+
+        remove("staging", &target).unwrap();
+
+        let cleaned: Value = serde_yaml::from_str(&fs::read_to_string(&target).unwrap()).unwrap();
+        assert_eq!(cleaned["contexts"].as_sequence().unwrap().len(), 1);
+        assert!(cleaned["clusters"].as_sequence().unwrap().is_empty());
+
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn test_kubeconfig_comes_from_the_first_server_that_answers() {
+        let replay = ReplayRunner::from_json(r#"[
+            {"program": "ssh", "args": ["54.0.0.2", "sudo cat /etc/rancher/rke2/rke2.yaml"], "stdout": "", "stderr": "Connection refused", "exit_code": 255},
+            {"program": "ssh", "args": ["54.0.0.3", "sudo cat /etc/rancher/rke2/rke2.yaml"], "stdout": "kind: Config", "stderr": "", "exit_code": 0}
+        ]"#).unwrap();
+        let kube_manager = KubeManager::new(&replay, &SshSettings::default());
+
+        let (server_ip, raw) = fetch(&kube_manager, &["54.0.0.2".to_string(), "54.0.0.3".to_string(), "54.0.0.4".to_string()]).unwrap();
+
+        assert_eq!(server_ip, "54.0.0.3");
+        assert_eq!(raw, "kind: Config");
+        assert!(replay.unused().is_empty());
+    }
+}
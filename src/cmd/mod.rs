@@ -1,21 +1,34 @@
 mod init;
+mod airgap;
 mod deploy;
+mod destroy;
 mod terraform;
-mod cloud_provider;
+pub mod cloud_provider;
+mod environment;
+mod journal;
+mod runner;
 mod kube_manager;
+mod kubeconfig;
+mod ssh;
+mod state;
+mod status;
+mod token;
+mod upgrade;
 
 use clap::ArgMatches;
 
 pub fn run(cli: ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
     match cli.subcommand() {
-        Some(("init", _)) => {
-            let command = init::InitCommand::new();
-
-            let args = cli.subcommand_matches("init").unwrap();
+        Some(("init", args)) => {
+            let command = init::InitCommand::new(runner::from_env()?);
 
             command.execute(args)
         },
-        Some(("deploy", args)) => deploy::handle(args),
+        Some(("deploy", args)) => deploy::handle(args, runner::from_env()?),
+        Some(("destroy", args)) => destroy::handle(args, runner::from_env()?),
+        Some(("kubeconfig", args)) => kubeconfig::handle(args, runner::from_env()?),
+        Some(("status", args)) => status::handle(args, runner::from_env()?),
+        Some(("upgrade", args)) => upgrade::handle(args, runner::from_env()?),
         _ => Ok(()),
     }
 }
\ No newline at end of file
@@ -0,0 +1,361 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::ssh::{CommandOutput, Ssh2Transport, SshTransport};
+use crate::cmd::state;
+use crate::config::SshSettings;
+use crate::error::SmedError;
+use crate::output::{self, debug};
+
+const REDACTED: &str = "********";
+
+// A local command smed wants to run, e.g. `terraform apply` or `aws configure`.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub current_dir: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    secrets: Vec<String>,
+    hide_output: bool,
+}
+
+impl Invocation {
+    pub fn new(program: &str) -> Invocation {
+        Invocation { program: program.to_string(), args: Vec::new(), current_dir: None, env: Vec::new(), secrets: Vec::new(), hide_output: false }
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Invocation
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    pub fn current_dir(mut self, directory: &str) -> Invocation {
+        self.current_dir = Some(PathBuf::from(directory));
+        self
+    }
+
+    pub fn envs(mut self, env: &[(String, String)]) -> Invocation {
+        self.env.extend(env.iter().cloned());
+        self
+    }
+
+    // Marks an argument as a credential, so it is never written to a fixture.
+    pub fn secret(mut self, value: &str) -> Invocation {
+        self.secrets.push(value.to_string());
+        self
+    }
+
+    // Keeps what the command prints out of the log and fixtures, for commands
+    // that print secrets like `terraform output -json`.
+    pub fn hide_output(mut self) -> Invocation {
+        self.hide_output = true;
+        self
+    }
+
+    // The arguments as recorded and replayed, with credentials masked.
+    fn recorded_args(&self) -> Vec<String> {
+        self.args.iter()
+            .map(|arg| if self.secrets.contains(arg) { REDACTED.to_string() } else { arg.clone() })
+            .collect()
+    }
+}
+
+// Everything smed runs outside of its own process goes through a runner:
+// local tools like terraform and the cloud CLIs, and commands on the nodes
+// through the SSH transport it hands out.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, Box<dyn std::error::Error>>;
+
+    fn transport(&self, ssh: &SshSettings) -> Box<dyn SshTransport>;
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let mut command = Command::new(&invocation.program);
+        command.args(&invocation.args).envs(invocation.env.iter().cloned());
+
+        if let Some(directory) = &invocation.current_dir {
+            command.current_dir(directory);
+        }
+
+        debug!("$ {} {}", invocation.program, invocation.recorded_args().join(" "));
+
+        let output = command.output().map_err(|e| SmedError::spawn(&invocation.program, e))?;
+
+        let output = CommandOutput {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+        };
+
+        // The full output only shows with `-v`, but always reaches the log file
+        for text in [&output.stdout, &output.stderr] {
+            if !invocation.hide_output && !text.trim().is_empty() {
+                debug!("{}", text.trim_end());
+            }
+        }
+
+        if !output.success() {
+            debug!("{} exited with code {}", invocation.program, output.exit_code);
+        }
+
+        Ok(output)
+    }
+
+    fn transport(&self, ssh: &SshSettings) -> Box<dyn SshTransport> {
+        Box::new(Ssh2Transport::new(ssh))
+    }
+}
+
+// One recorded command and what it printed. SSH commands are recorded with
+// `ssh` as the program and the host and command as arguments, uploads with
+// `upload` and the host and remote path. The working directory and
+// environment are left out, arguments marked as secret and the secrets smed
+// knows of (like the join token) are masked everywhere, and hidden output is
+// left out, so fixtures hold no credentials and replay from any directory
+// with any env file. A fixture replaying a deploy needs made up values in the
+// stdout of `terraform output -json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub program: String,
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub exit_code: i32,
+}
+
+impl Exchange {
+    fn ssh(host: &str, command: &str) -> (String, Vec<String>) {
+        ("ssh".to_string(), vec![host.to_string(), command.to_string()])
+    }
+
+    // Only the destination is recorded, the local path differs between machines.
+    fn upload(host: &str, remote: &str) -> (String, Vec<String>) {
+        ("upload".to_string(), vec![host.to_string(), remote.to_string()])
+    }
+
+    fn output(&self) -> CommandOutput {
+        CommandOutput { stdout: self.stdout.clone(), stderr: self.stderr.clone(), exit_code: self.exit_code }
+    }
+}
+
+// Runs everything through another runner and saves each exchange to a
+// fixture file as it happens.
+#[derive(Clone)]
+pub struct RecordingRunner {
+    runner: Arc<dyn CommandRunner>,
+    path: PathBuf,
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+}
+
+impl RecordingRunner {
+    pub fn new(runner: Arc<dyn CommandRunner>, path: &Path) -> RecordingRunner {
+        RecordingRunner { runner, path: path.to_path_buf(), exchanges: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    fn record(&self, program: String, args: Vec<String>, output: &CommandOutput, hide_output: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+
+        exchanges.push(Exchange {
+            program,
+            args: args.iter().map(|arg| output::redact(arg)).collect(),
+            stdout: if hide_output { String::new() } else { output::redact(&output.stdout) },
+            stderr: output::redact(&output.stderr),
+            exit_code: output.exit_code,
+        });
+
+        state::write_private(&self.path, &serde_json::to_string_pretty(&*exchanges)?)
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let output = self.runner.run(invocation)?;
+
+        self.record(invocation.program.clone(), invocation.recorded_args(), &output, invocation.hide_output)?;
+
+        Ok(output)
+    }
+
+    fn transport(&self, ssh: &SshSettings) -> Box<dyn SshTransport> {
+        Box::new(RecordingTransport { transport: self.runner.transport(ssh), recorder: self.clone() })
+    }
+}
+
+struct RecordingTransport {
+    transport: Box<dyn SshTransport>,
+    recorder: RecordingRunner,
+}
+
+impl SshTransport for RecordingTransport {
+    fn exec(&self, host: &str, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let output = self.transport.exec(host, command)?;
+
+        let (program, args) = Exchange::ssh(host, command);
+        self.recorder.record(program, args, &output, false)?;
+
+        Ok(output)
+    }
+
+    fn upload(&self, host: &str, local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.transport.upload(host, local, remote)?;
+
+        let (program, args) = Exchange::upload(host, remote);
+        self.recorder.record(program, args, &CommandOutput { stdout: String::new(), stderr: String::new(), exit_code: 0 }, false)
+    }
+}
+
+// Answers every command from a fixture instead of running it. Each recorded
+// exchange is used once, in the order it was recorded among those with the
+// same program and arguments, so commands that run concurrently replay fine.
+// Arguments are compared with the known secrets masked, as they were recorded.
+#[derive(Clone)]
+pub struct ReplayRunner {
+    exchanges: Arc<Mutex<Vec<(Exchange, bool)>>>,
+}
+
+impl ReplayRunner {
+    pub fn load(path: &Path) -> Result<ReplayRunner, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read fixture {}: {}", path.display(), e))?;
+
+        Self::from_json(&contents).map_err(|e| format!("Invalid fixture {}: {}", path.display(), e).into())
+    }
+
+    pub fn from_json(json: &str) -> Result<ReplayRunner, Box<dyn std::error::Error>> {
+        let exchanges: Vec<Exchange> = serde_json::from_str(json)?;
+
+        Ok(ReplayRunner { exchanges: Arc::new(Mutex::new(exchanges.into_iter().map(|exchange| (exchange, false)).collect())) })
+    }
+
+    // Exchanges the fixture holds that nothing asked for.
+    #[cfg(test)]
+    pub fn unused(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().iter()
+            .filter(|(_, used)| !used)
+            .map(|(exchange, _)| exchange.clone())
+            .collect()
+    }
+
+    fn replay(&self, program: &str, args: &[String]) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+
+        let redacted = |args: &[String]| args.iter().map(|arg| output::redact(arg)).collect::<Vec<_>>();
+        let args = redacted(args);
+
+        let (exchange, used) = exchanges.iter_mut()
+            .find(|(exchange, used)| !used && exchange.program == program && redacted(&exchange.args) == args)
+            .ok_or_else(|| format!("No recorded output left for `{} {}`", program, args.join(" ")))?;
+
+        *used = true;
+
+        Ok(exchange.output())
+    }
+}
+
+impl CommandRunner for ReplayRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        self.replay(&invocation.program, &invocation.recorded_args())
+    }
+
+    fn transport(&self, _ssh: &SshSettings) -> Box<dyn SshTransport> {
+        Box::new(self.clone())
+    }
+}
+
+impl SshTransport for ReplayRunner {
+    fn exec(&self, host: &str, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let (program, args) = Exchange::ssh(host, command);
+
+        self.replay(&program, &args)
+    }
+
+    fn upload(&self, host: &str, _local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (program, args) = Exchange::upload(host, remote);
+
+        let output = self.replay(&program, &args)?;
+
+        if output.success() {
+            Ok(())
+        } else {
+            Err(output.stderr.trim().to_string().into())
+        }
+    }
+}
+
+// The real runner, or with SMED_RECORD=<file> the real runner saving every
+// exchange to that file, or with SMED_REPLAY=<file> a replay of such a file.
+pub fn from_env() -> Result<Arc<dyn CommandRunner>, Box<dyn std::error::Error>> {
+    if let Some(path) = env::var_os("SMED_REPLAY") {
+        return Ok(Arc::new(ReplayRunner::load(Path::new(&path))?));
+    }
+
+    if let Some(path) = env::var_os("SMED_RECORD") {
+        return Ok(Arc::new(RecordingRunner::new(Arc::new(SystemRunner), Path::new(&path))));
+    }
+
+    Ok(Arc::new(SystemRunner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorded_exchanges_replay_once_each() {
+        let path = env::temp_dir().join(format!("smed-fixture-{}.json", std::process::id()));
+
+        let recorder = RecordingRunner::new(Arc::new(SystemRunner), &path);
+        let recorded = recorder.run(&Invocation::new("sh").args(["-c", "echo out; echo err >&2; exit 3"])).unwrap();
+        assert_eq!(recorded.stdout, "out\n");
+
+        let replay = ReplayRunner::load(&path).unwrap();
+        let replayed = replay.run(&Invocation::new("sh").args(["-c", "echo out; echo err >&2; exit 3"])).unwrap();
+
+        assert_eq!((replayed.stdout, replayed.stderr, replayed.exit_code), ("out\n".to_string(), "err\n".to_string(), 3));
+        assert!(replay.unused().is_empty());
+        assert!(replay.run(&Invocation::new("sh").args(["-c", "echo out; echo err >&2; exit 3"])).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fixtures_hold_no_secrets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("smed-fixture-secrets-{}.json", std::process::id()));
+        output::add_secret("recorded-j0in-token");
+
+        let recorder = RecordingRunner::new(Arc::new(SystemRunner), &path);
+        recorder.run(&Invocation::new("sh").args(["-c", "echo recorded-j0in-token >&2", "aws-k3y"]).secret("aws-k3y")).unwrap();
+        recorder.run(&Invocation::new("echo").args(["{\"rke2_token\": \"hidden\"}"]).hide_output()).unwrap();
+
+        let fixture = fs::read_to_string(&path).unwrap();
+        assert!(!fixture.contains("recorded-j0in-token"));
+        assert!(!fixture.contains("aws-k3y"));
+        assert!(!fixture.contains("\"stdout\": \"{"));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Replays with the secret masked the same way
+        let replay = ReplayRunner::load(&path).unwrap();
+        let replayed = replay.run(&Invocation::new("sh").args(["-c", "echo recorded-j0in-token >&2", "aws-k3y"]).secret("aws-k3y")).unwrap();
+        assert_eq!(replayed.stderr, "********\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
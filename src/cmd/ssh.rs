@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ssh2::{Channel, CheckResult, KnownHostFileKind, Session};
+
+use crate::config::{HostKeyChecking, SshAuth, SshSettings};
+use crate::output::warning;
+
+// For transports built from settings that didn't come from a spec
+const DEFAULT_KNOWN_HOSTS: &str = "~/.ssh/known_hosts";
+
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+pub trait SshTransport: Send + Sync {
+    fn exec(&self, host: &str, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>>;
+
+    // Copies the local file to `remote` on the host, creating its directory.
+    fn upload(&self, host: &str, local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+// One authenticated session per host, reused by every command run on it. A
+// host runs one command at a time, which also lets `exec` switch its session
+// to non-blocking while it reads.
+pub struct Ssh2Transport {
+    settings: SshSettings,
+    known_hosts_lock: Mutex<()>,
+    sessions: Mutex<HashMap<String, Arc<Mutex<Option<Session>>>>>,
+}
+
+impl Ssh2Transport {
+    pub fn new(settings: &SshSettings) -> Ssh2Transport {
+        Ssh2Transport { settings: settings.clone(), known_hosts_lock: Mutex::new(()), sessions: Mutex::new(HashMap::new()) }
+    }
+
+    // Runs `f` on a channel of the host's session, connecting first if there is
+    // none. A session the host dropped since its last command is replaced once.
+    fn with_channel<T>(&self, host: &str, f: impl FnOnce(&Session, Channel) -> Result<T, Box<dyn std::error::Error>>) -> Result<T, Box<dyn std::error::Error>> {
+        let slot = self.sessions.lock().unwrap().entry(host.to_string()).or_default().clone();
+        let mut session = slot.lock().unwrap();
+
+        let channel = match session.as_ref().map(Session::channel_session) {
+            Some(Ok(channel)) => channel,
+            _ => {
+                *session = Some(self.connect(host)?);
+                session.as_ref().unwrap().channel_session()?
+            },
+        };
+
+        let result = f(session.as_ref().unwrap(), channel);
+
+        if result.is_err() {
+            *session = None;
+        }
+
+        result
+    }
+
+    fn connect(&self, host: &str) -> Result<Session, Box<dyn std::error::Error>> {
+        let tcp = TcpStream::connect((host, self.settings.port))
+            .map_err(|e| format!("Failed to connect to {}:{}: {}", host, self.settings.port, e))?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        self.verify_host_key(&session, host)?;
+
+        match self.settings.auth {
+            SshAuth::Key => {
+                let key_path = expand_tilde(&self.settings.private_key_path);
+                session.userauth_pubkey_file(&self.settings.user, None, Path::new(&key_path), None)
+                    .map_err(|e| format!("Key authentication as {} on {} failed: {}", self.settings.user, host, e))?;
+            },
+            SshAuth::Agent => {
+                session.userauth_agent(&self.settings.user)
+                    .map_err(|e| format!("Agent authentication as {} on {} failed: {}", self.settings.user, host, e))?;
+            },
+        }
+
+        Ok(session)
+    }
+
+    fn verify_host_key(&self, session: &Session, host: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let _guard = self.known_hosts_lock.lock().unwrap();
+
+        let known_hosts_path = expand_tilde(self.settings.known_hosts_path.as_deref().unwrap_or(DEFAULT_KNOWN_HOSTS));
+        let known_hosts_path = Path::new(&known_hosts_path);
+
+        let mut known_hosts = session.known_hosts()?;
+
+        if known_hosts_path.exists() {
+            known_hosts.read_file(known_hosts_path, KnownHostFileKind::OpenSSH)?;
+        }
+
+        let (key, key_type) = session.host_key().ok_or(format!("{} did not send a host key", host))?;
+
+        match known_hosts.check_port(host, self.settings.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound if self.settings.host_key_checking == HostKeyChecking::AcceptNew => {
+                known_hosts.add(&Self::known_hosts_name(host, self.settings.port), key, "added by smed", key_type.into())?;
+
+                if let Some(parent) = known_hosts_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                known_hosts.write_file(known_hosts_path, KnownHostFileKind::OpenSSH)?;
+
+                warning!("⚠ Added host key for {} to {}", host, known_hosts_path.display());
+                Ok(())
+            },
+            CheckResult::NotFound => {
+                Err(format!("Host key for {} is not in {} and host_key_checking is strict", host, known_hosts_path.display()).into())
+            },
+            CheckResult::Mismatch => {
+                Err(format!("Host key for {} does not match the one in {}, refusing to connect", host, known_hosts_path.display()).into())
+            },
+            CheckResult::Failure => Err(format!("Failed to check the host key for {}", host).into()),
+        }
+    }
+
+    fn known_hosts_name(host: &str, port: u16) -> String {
+        if port == 22 {
+            host.to_string()
+        } else {
+            format!("[{}]:{}", host, port)
+        }
+    }
+}
+
+impl SshTransport for Ssh2Transport {
+    fn exec(&self, host: &str, command: &str) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        self.with_channel(host, |session, mut channel| {
+            channel.exec(command)?;
+
+            // Both streams share the channel's window: reading one to the end
+            // before the other stalls a command that fills the other first.
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            let mut buffer = [0; 32 * 1024];
+
+            session.set_blocking(false);
+            let read = (|| -> io::Result<()> {
+                while !channel.eof() {
+                    let mut idle = true;
+
+                    for (mut stream, output) in [(channel.stream(0), &mut stdout), (channel.stderr(), &mut stderr)] {
+                        match stream.read(&mut buffer) {
+                            Ok(n) => {
+                                output.extend_from_slice(&buffer[..n]);
+                                idle &= n == 0;
+                            },
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    if idle {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                }
+
+                Ok(())
+            })();
+            session.set_blocking(true);
+            read?;
+
+            // Whatever arrived along with the end of the output
+            channel.read_to_end(&mut stdout)?;
+            channel.stderr().read_to_end(&mut stderr)?;
+
+            channel.wait_close()?;
+
+            Ok(CommandOutput {
+                stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                exit_code: channel.exit_status()?,
+            })
+        })
+    }
+
+    // Streams the file into `cat` on the host rather than using SCP or SFTP,
+    // which need the directory to exist and aren't enabled on every server.
+    fn upload(&self, host: &str, local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = File::open(local).map_err(|e| format!("Failed to open {}: {}", local.display(), e))?;
+
+        let (exit_code, stderr) = self.with_channel(host, |_, mut channel| {
+            channel.exec(&format!("umask 077 && mkdir -p \"$(dirname '{0}')\" && cat > '{0}'", remote))?;
+
+            io::copy(&mut file, &mut channel)?;
+            channel.send_eof()?;
+
+            let mut stderr = String::new();
+            channel.stderr().read_to_string(&mut stderr)?;
+
+            channel.wait_close()?;
+
+            Ok((channel.exit_status()?, stderr))
+        })?;
+
+        match exit_code {
+            0 => Ok(()),
+            exit_code => Err(format!("Failed to upload {} to {}:{} (exit code {}): {}", local.display(), host, remote, exit_code, stderr.trim()).into()),
+        }
+    }
+}
+
+pub fn expand_tilde(path: &str) -> String {
+    if path.starts_with("~") {
+        let home = std::env::var("HOME").unwrap_or_default();
+        path.replacen("~", &home, 1)
+    } else {
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs a reachable sshd, e.g. `docker run -d -p 2222:2222 -e PUBLIC_KEY="$(cat ~/.ssh/id_rsa.pub)"
+    // -e USER_NAME=ubuntu linuxserver/openssh-server`, then run with
+    // `SMED_TEST_SSH_HOST=127.0.0.1 SMED_TEST_SSH_PORT=2222 cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_exec_captures_output_and_exit_code() {
+        let host = std::env::var("SMED_TEST_SSH_HOST").unwrap();
+        let settings = SshSettings {
+            port: std::env::var("SMED_TEST_SSH_PORT").map(|p| p.parse().unwrap()).unwrap_or(22),
+            known_hosts_path: Some(std::env::temp_dir().join("smed_known_hosts").display().to_string()),
+            ..Default::default()
+        };
+        let transport = Ssh2Transport::new(&settings);
+
+        let output = transport.exec(&host, "echo out; echo err >&2; exit 3").unwrap();
+
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+        assert_eq!(output.exit_code, 3);
+
+        // More stderr than the channel's window holds before any stdout
+        let output = transport.exec(&host, "head -c 4000000 /dev/zero | tr '\\0' e >&2; echo out").unwrap();
+
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr.len(), 4000000);
+
+        let local = std::env::temp_dir().join(format!("smed-upload-{}", std::process::id()));
+        std::fs::write(&local, "artifact\n").unwrap();
+
+        transport.upload(&host, &local, "/tmp/smed-upload/artifact").unwrap();
+        assert_eq!(transport.exec(&host, "cat /tmp/smed-upload/artifact").unwrap().stdout, "artifact\n");
+
+        std::fs::remove_file(&local).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// Everything smed keeps about a deployed cluster (journal, join token,
+// kubeconfig) lives in this directory inside the terraform directory.
+pub const STATE_DIRECTORY: &str = ".smed";
+
+// The provider and region `smed init` was run with, which outlives the cluster.
+pub const INIT_DEFAULTS: &str = "init.yaml";
+
+pub fn path(terraform_directory: &str, file: &str) -> PathBuf {
+    Path::new(terraform_directory).join(STATE_DIRECTORY).join(file)
+}
+
+pub fn write_private(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.write_all(contents.as_bytes())?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    Ok(())
+}
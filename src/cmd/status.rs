@@ -0,0 +1,159 @@
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::json;
+
+use crate::cmd::cloud_provider;
+use crate::cmd::environment::Environment;
+use crate::cmd::kube_manager::{ClusterNode, KubeManager, NodeHealth};
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::terraform::TerraformClient;
+use crate::error::SmedError;
+use crate::output::{self, Color, info, success, warning};
+
+const HEADERS: [&str; 6] = ["ROLE", "NODE", "IP", "SERVICE", "READY", "VERSION"];
+
+pub fn handle(args: &ArgMatches, runner: Arc<dyn CommandRunner>) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = Environment::from_args(args)?;
+
+    let terraform_directory = &environment.terraform_directory(args);
+
+    let spec = environment.spec(args)?;
+    let config = environment.config(args)?;
+
+    let terraform_env = cloud_provider::terraform_env(&spec.provider, &config)?;
+
+    let terraform = TerraformClient::new(runner.clone());
+
+    terraform.attach(terraform_directory, &spec)?;
+    let output = terraform.output(terraform_directory, &terraform_env)?;
+    let nodes = KubeManager::nodes(&output)?;
+
+    let kube_manager = KubeManager::new(runner.as_ref(), &spec.ssh);
+
+    info!("🩺 Checking {} node(s)...", nodes.len());
+
+    // Rancher runs its own cluster, every other node belongs to the one
+    // served by etcd.
+    let rancher = readiness(&kube_manager, &nodes, &["Rancher"]);
+    let downstream = readiness(&kube_manager, &nodes, &["Etcd", "Control Plane"]);
+
+    let health: Vec<Result<NodeHealth, String>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = nodes.iter()
+            .map(|node| scope.spawn(|| kube_manager.node_health(&node.ip, node.service()).map_err(|e| e.to_string())))
+            .collect();
+
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err("status check panicked".to_string())))
+            .collect()
+    });
+
+    let mut rows = Vec::new();
+    let mut unhealthy = 0;
+
+    for (node, health) in nodes.iter().zip(&health) {
+        let cluster = if node.role == "Rancher" { &rancher } else { &downstream };
+
+        let (service_state, ready, version) = match health {
+            Ok(health) => {
+                let ready = cluster.as_ref().and_then(|cluster| cluster.get(&health.hostname).copied());
+                (health.service_state.clone(), ready, health.version.clone())
+            },
+            Err(error) => (format!("unreachable ({})", error), None, "-".to_string()),
+        };
+
+        let healthy = service_state == "active" && ready == Some(true);
+        if !healthy {
+            unhealthy += 1;
+        }
+
+        let ready = match ready {
+            Some(true) => "Ready",
+            Some(false) => "NotReady",
+            None => "unknown",
+        };
+
+        rows.push((healthy, [node.role.to_string(), node.name.clone(), node.ip.clone(), service_state, ready.to_string(), version]));
+    }
+
+    print_table(&rows);
+
+    let report: Vec<_> = rows.iter()
+        .map(|(healthy, [role, node, ip, service, ready, version])| json!({
+            "role": role, "node": node, "ip": ip, "service": service, "ready": ready, "version": version, "healthy": healthy,
+        }))
+        .collect();
+    output::record("nodes", report);
+
+    if unhealthy == 0 {
+        success!("✔ All {} node(s) are healthy", nodes.len());
+        Ok(())
+    } else {
+        Err(SmedError::Unhealthy(format!("{} of {} node(s) are unhealthy", unhealthy, nodes.len())).into())
+    }
+}
+
+// Node readiness as seen by the first API server of `roles` that answers, or
+// None when none can be asked, in which case readiness is reported as unknown.
+fn readiness(kube_manager: &KubeManager, nodes: &[ClusterNode], roles: &[&str]) -> Option<HashMap<String, bool>> {
+    let servers = roles.iter().flat_map(|role| nodes.iter().filter(move |node| node.role == *role));
+
+    for server in servers {
+        match kube_manager.node_readiness(&server.ip) {
+            Ok(readiness) => return Some(readiness),
+            Err(error) => warning!("⚠ Could not read node readiness from {} {}: {}", server.name, server.ip, error),
+        }
+    }
+
+    None
+}
+
+fn print_table(rows: &[(bool, [String; 6])]) {
+    let mut widths = HEADERS.map(str::len);
+    for (_, columns) in rows {
+        for (width, column) in widths.iter_mut().zip(columns) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    let format_row = |columns: [&str; 6]| -> String {
+        columns.iter().zip(widths)
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    info!("{}", format_row(HEADERS));
+    for (healthy, columns) in rows {
+        let color = if *healthy { Color::Green } else { Color::Red };
+        info!("{}", output::paint(color, &format_row(columns.each_ref().map(String::as_str))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::runner::ReplayRunner;
+    use crate::config::SshSettings;
+
+    #[test]
+    fn test_readiness_comes_from_the_first_server_that_answers() {
+        let replay = ReplayRunner::from_json(r#"[
+            {"program": "ssh", "args": ["54.0.0.2", "sudo /var/lib/rancher/rke2/bin/kubectl --kubeconfig /etc/rancher/rke2/rke2.yaml get nodes -o json"], "stdout": "", "stderr": "Connection refused", "exit_code": 255},
+            {"program": "ssh", "args": ["54.0.0.3", "sudo /var/lib/rancher/rke2/bin/kubectl --kubeconfig /etc/rancher/rke2/rke2.yaml get nodes -o json"], "stdout": "{\"items\": [{\"metadata\": {\"name\": \"ip-172-31-0-10\"}, \"status\": {\"conditions\": [{\"type\": \"Ready\", \"status\": \"True\"}]}}]}", "stderr": "", "exit_code": 0}
+        ]"#).unwrap();
+        let kube_manager = KubeManager::new(&replay, &SshSettings::default());
+
+        let node = |role: &'static str, ip: &str| ClusterNode { role, name: role.to_lowercase(), ip: ip.to_string() };
+        let nodes = [node("Etcd", "54.0.0.2"), node("Control Plane", "54.0.0.3"), node("Worker", "54.0.0.4")];
+
+        let readiness = readiness(&kube_manager, &nodes, &["Etcd", "Control Plane"]).unwrap();
+
+        assert_eq!(readiness.get("ip-172-31-0-10"), Some(&true));
+        assert!(replay.unused().is_empty());
+        assert!(super::readiness(&kube_manager, &nodes, &["Rancher"]).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use crate::cmd::state;
+use crate::cmd::terraform::{TerraformOutput, TerraformValue};
+
+const TOKEN_LENGTH: usize = 48;
+
+const BOOTSTRAP_PASSWORD_FILE: &str = "rancher-password";
+
+// The cluster join token comes from the sensitive `rke2_token` Terraform output
+// when the template defines one, otherwise from the token stored by a previous
+// deploy, otherwise a new one is generated. Whatever is used is stored with
+// owner-only permissions so later commands reuse it.
+pub fn resolve(terraform_directory: &str, output: &TerraformOutput) -> Result<String, Box<dyn std::error::Error>> {
+    let path = token_path(terraform_directory);
+
+    let token = match output.get("rke2_token") {
+        Some(TerraformValue::String { value }) if !value.is_empty() => value.clone(),
+        _ => match read(terraform_directory)? {
+            Some(token) => token,
+            None => generate(),
+        },
+    };
+
+    state::write_private(&path, &token)?;
+
+    Ok(token)
+}
+
+pub fn read(terraform_directory: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    read_secret(&token_path(terraform_directory))
+}
+
+// The initial password of Rancher's `admin` user, generated on the first
+// deploy and stored with owner-only permissions so redeploys keep it.
+pub fn bootstrap_password(terraform_directory: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let path = bootstrap_password_path(terraform_directory);
+
+    if let Some(password) = read_secret(&path)? {
+        return Ok(password);
+    }
+
+    let password = generate();
+    state::write_private(&path, &password)?;
+
+    Ok(password)
+}
+
+pub fn bootstrap_password_path(terraform_directory: &str) -> PathBuf {
+    state::path(terraform_directory, BOOTSTRAP_PASSWORD_FILE)
+}
+
+fn read_secret(path: &Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let secret = fs::read_to_string(path)?.trim().to_string();
+
+    Ok(Some(secret).filter(|secret| !secret.is_empty()))
+}
+
+fn generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn token_path(terraform_directory: &str) -> PathBuf {
+    state::path(terraform_directory, "token")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_resolve_generates_and_reuses_a_private_token() {
+        let directory = std::env::temp_dir().join(format!("smed-token-{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+
+        let token = resolve(directory, &TerraformOutput::new()).unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_eq!(resolve(directory, &TerraformOutput::new()).unwrap(), token);
+
+        let mode = fs::metadata(token_path(directory)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut output = TerraformOutput::new();
+        output.insert("rke2_token".to_string(), TerraformValue::String { value: "from-terraform".to_string() });
+        assert_eq!(resolve(directory, &output).unwrap(), "from-terraform");
+        assert_eq!(read(directory).unwrap().unwrap(), "from-terraform");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_bootstrap_password_is_generated_once() {
+        let directory = std::env::temp_dir().join(format!("smed-password-{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+
+        let password = bootstrap_password(directory).unwrap();
+        assert_eq!(password.len(), TOKEN_LENGTH);
+        assert_eq!(bootstrap_password(directory).unwrap(), password);
+
+        let mode = fs::metadata(bootstrap_password_path(directory)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
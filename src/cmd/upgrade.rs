@@ -0,0 +1,208 @@
+use clap::ArgMatches;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::cmd::airgap::Artifacts;
+use crate::cmd::cloud_provider;
+use crate::cmd::environment::Environment;
+use crate::cmd::kube_manager::{ClusterNode, KubeManager, NodeReport};
+use crate::cmd::runner::CommandRunner;
+use crate::cmd::terraform::TerraformClient;
+use crate::config::is_rke2_version;
+use crate::error::SmedError;
+use crate::output::{self, Color, info, success};
+
+// The order nodes are upgraded in: the datastore first, then the rest of the
+// control plane, then the nodes that only run workloads.
+const ROLE_ORDER: [&str; 4] = ["Etcd", "Control Plane", "Rancher", "Worker"];
+
+pub fn handle(args: &ArgMatches, runner: Arc<dyn CommandRunner>) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = Environment::from_args(args)?;
+
+    let terraform_directory = &environment.terraform_directory(args);
+
+    let spec = environment.spec(args)?;
+    let config = environment.config(args)?;
+
+    let version = args.get_one::<String>("rke2-version").or(spec.rke2.version.as_ref())
+        .ok_or_else(|| SmedError::ConfigInvalid("Pass --rke2-version or set rke2.version in the spec to the version to upgrade to".to_string()))?;
+    validate_version(version)?;
+
+    let artifacts = args.get_one::<String>("rke2-artifacts").or(spec.rke2.artifacts_dir.as_ref())
+        .map(|directory| Artifacts::load(directory))
+        .transpose()?;
+
+    let terraform_env = cloud_provider::terraform_env(&spec.provider, &config)?;
+
+    let terraform = TerraformClient::new(runner.clone());
+
+    terraform.attach(terraform_directory, &spec)?;
+    let output = terraform.output(terraform_directory, &terraform_env)?;
+    let nodes = upgrade_order(KubeManager::nodes(&output)?);
+
+    let kube_manager = KubeManager::new(runner.as_ref(), &spec.ssh).with_artifacts(artifacts);
+
+    info!("⬆ Upgrading {} node(s) to RKE2 {}, one at a time...", nodes.len(), version);
+
+    let started = Instant::now();
+    let mut failed = None;
+
+    for node in &nodes {
+        info!("🔧 Upgrading {} {} ({})...", node.name, node.ip, node.role);
+
+        if let Err(error) = kube_manager.upgrade_node(node, server_ip(node, &nodes), version) {
+            failed = Some((node, error));
+            break;
+        }
+    }
+
+    output::timing("upgrade", started);
+    output::record("rke2_version", version);
+    output::record("nodes", kube_manager.reports());
+
+    let reports = kube_manager.reports();
+    let upgraded = reports.iter().filter(|report| report.status != "failed").count();
+    print_report(&nodes, &reports, version);
+
+    match failed {
+        None => {
+            success!("✔ All {} node(s) run RKE2 {}", nodes.len(), version);
+            Ok(())
+        },
+        Some((node, _)) => {
+            let (step, cordoned) = reports.last().map_or(("Read hostname", false), failed_step);
+
+            Err(SmedError::SshStepFailed(format!(
+                "Upgrade stopped at {} {} on \"{}\": {} node(s) upgraded, {} not upgraded. {}, fix it and run `smed upgrade` again",
+                node.name, node.ip, step, upgraded, nodes.len() - upgraded - 1,
+                if cordoned { "The node is still cordoned" } else { "The node was not cordoned" },
+            )).into())
+        },
+    }
+}
+
+// The step the upgrade of `report`'s node failed at, and whether the node was
+// cordoned by then: reading its hostname and uploading artifacts come first.
+fn failed_step(report: &NodeReport) -> (&str, bool) {
+    let step = report.steps.iter().find(|step| step.status == "failed").map_or("", |step| step.description.as_str());
+    let cordoned = report.steps.iter().any(|step| step.description == "Cordon node" && step.status == "done");
+
+    (step, cordoned)
+}
+
+fn validate_version(version: &str) -> Result<(), SmedError> {
+    if is_rke2_version(version) {
+        Ok(())
+    } else {
+        Err(SmedError::ConfigInvalid(format!("Invalid RKE2 version '{}', expected something like v1.30.4+rke2r1", version)))
+    }
+}
+
+fn upgrade_order(mut nodes: Vec<ClusterNode>) -> Vec<ClusterNode> {
+    nodes.sort_by_key(|node| ROLE_ORDER.iter().position(|role| *role == node.role));
+    nodes
+}
+
+// The node whose API server cordons, drains and waits for `node`, which must
+// not be `node` itself: its API server goes down with the upgrade. Rancher
+// runs a cluster of its own, so it has none and is only waited for.
+fn server_ip<'a>(node: &ClusterNode, nodes: &'a [ClusterNode]) -> Option<&'a str> {
+    if node.role == "Rancher" {
+        return None;
+    }
+
+    let other = |role: &str| nodes.iter().find(|other| other.role == role && other.ip != node.ip);
+
+    other("Etcd").or_else(|| other("Control Plane")).map(|server| server.ip.as_str())
+}
+
+fn print_report(nodes: &[ClusterNode], reports: &[NodeReport], version: &str) {
+    for node in nodes {
+        let line = match reports.iter().find(|report| report.ip == node.ip) {
+            Some(report) if report.status == "current" => output::paint(Color::Green, &format!("✔ {} {}: already on {}", node.name, node.ip, version)),
+            Some(report) if report.status == "upgraded" => output::paint(Color::Green, &format!("✔ {} {}: upgraded", node.name, node.ip)),
+            Some(report) => output::paint(Color::Red, &format!("✖ {} {}: {}", node.name, node.ip, report.error.as_deref().unwrap_or(""))),
+            None => output::paint(Color::Yellow, &format!("- {} {}: not upgraded", node.name, node.ip)),
+        };
+
+        info!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::build_cli;
+    use crate::cmd::kube_manager::StepReport;
+    use crate::cmd::runner::ReplayRunner;
+    use crate::error;
+    use std::fs;
+
+    #[test]
+    fn test_upgrade_stops_at_the_first_node_that_does_not_come_back() {
+        let directory = std::env::temp_dir().join(format!("smed-upgrade-{}", std::process::id()));
+        let terraform_directory = directory.join("terraform");
+        fs::create_dir_all(terraform_directory.join(".terraform")).unwrap();
+
+        let spec_path = directory.join("smed.yaml");
+        fs::write(&spec_path, "name: fixture\nnodes:\n  worker:\n    count: 1\n").unwrap();
+
+        let env_path = directory.join(".env");
+        fs::write(&env_path, "AWS_ACCESS_KEY_ID=test_access_key\nAWS_SECRET_ACCESS_KEY=test_secret_key\n").unwrap();
+
+        let args = build_cli().get_matches_from([
+            "smed", "upgrade",
+            "-t", terraform_directory.to_str().unwrap(),
+            "-s", spec_path.to_str().unwrap(),
+            "-e", env_path.to_str().unwrap(),
+            "--rke2-version", "v1.31.1+rke2r1",
+        ]);
+
+        let replay = ReplayRunner::from_json(include_str!("../../tests/fixtures/upgrade_aws.json")).unwrap();
+
+        let error = handle(args.subcommand_matches("upgrade").unwrap(), Arc::new(replay.clone())).unwrap_err();
+
+        assert_eq!(error::exit_code(error.as_ref()), 6);
+        assert_eq!(error.to_string(), "Upgrade stopped at rancher 54.0.0.1 on \"Wait for the node to be Ready\": 2 node(s) upgraded, 1 not upgraded. The node was not cordoned, fix it and run `smed upgrade` again");
+        assert!(replay.unused().is_empty());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_validate_version() {
+        assert!(validate_version("v1.30.4+rke2r1").is_ok());
+        assert!(validate_version("1.30.4").is_err());
+        assert!(validate_version("v1.30'; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn test_server_ip_is_never_the_node_being_upgraded() {
+        let node = |role: &'static str, ip: &str| ClusterNode { role, name: role.to_lowercase(), ip: ip.to_string() };
+        let nodes = [node("Etcd", "54.0.0.2"), node("Control Plane", "54.0.0.3"), node("Rancher", "54.0.0.1"), node("Worker", "54.0.0.4")];
+
+        assert_eq!(server_ip(&nodes[0], &nodes), Some("54.0.0.3"));
+        assert_eq!(server_ip(&nodes[1], &nodes), Some("54.0.0.2"));
+        assert_eq!(server_ip(&nodes[2], &nodes), None);
+        assert_eq!(server_ip(&nodes[3], &nodes), Some("54.0.0.2"));
+        assert_eq!(server_ip(&nodes[0], &nodes[..1]), None);
+    }
+
+    #[test]
+    fn test_failed_step_tells_whether_the_node_was_cordoned() {
+        let report = |steps: &[(&str, &'static str)]| NodeReport {
+            role: "Worker".to_string(),
+            node: "worker-1".to_string(),
+            ip: "54.0.0.4".to_string(),
+            status: "failed",
+            error: None,
+            duration_ms: 0,
+            steps: steps.iter().map(|(description, status)| StepReport { description: description.to_string(), status, duration_ms: 0 }).collect(),
+        };
+
+        assert_eq!(failed_step(&report(&[("Read hostname", "failed")])), ("Read hostname", false));
+        assert_eq!(failed_step(&report(&[("Read hostname", "done"), ("Upload RKE2 artifacts", "failed")])), ("Upload RKE2 artifacts", false));
+        assert_eq!(failed_step(&report(&[("Read hostname", "done"), ("Cordon node", "failed")])), ("Cordon node", false));
+        assert_eq!(failed_step(&report(&[("Read hostname", "done"), ("Cordon node", "done"), ("Drain node", "failed")])), ("Drain node", true));
+    }
+}
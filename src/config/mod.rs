@@ -1,21 +1,53 @@
+mod spec;
+
 use std::env;
 use std::path::Path;
 
-#[derive(Debug)]
+pub use spec::{is_rke2_version, Backend, ClusterSpec, HostKeyChecking, RancherSettings, Rke2Settings, SshAuth, SshSettings};
+
+use crate::error::SmedError;
+
+#[derive(Debug, Default)]
 pub struct Config {
-    pub aws_access_key: String,
-    pub aws_secret_key: String,
+    pub aws_access_key: Option<String>,
+    pub aws_secret_key: Option<String>,
+    pub gcp_credentials_path: Option<String>,
+    pub gcp_project_id: Option<String>,
+    pub azure_tenant_id: Option<String>,
+    pub azure_client_id: Option<String>,
+    pub azure_client_secret: Option<String>,
+    pub azure_subscription_id: Option<String>,
 }
 
 
 impl Config {
     pub fn from_env(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let path = Path::new(path);
-        dotenvy::from_path(path).ok();
+        Self::from_env_files(&[path])
+    }
+
+    // Reads the env files in order, a variable set by an earlier file or by the
+    // environment itself wins.
+    pub fn from_env_files(paths: &[&str]) -> Result<Self, Box<dyn std::error::Error>> {
+        for path in paths {
+            dotenvy::from_path(Path::new(path)).ok();
+        }
 
         Ok(Self {
-            aws_access_key: env::var("AWS_ACCESS_KEY_ID")?,
-            aws_secret_key: env::var("AWS_SECRET_ACCESS_KEY")?,
+            aws_access_key: env::var("AWS_ACCESS_KEY_ID").ok(),
+            aws_secret_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+            gcp_credentials_path: env::var("GOOGLE_APPLICATION_CREDENTIALS").ok(),
+            gcp_project_id: env::var("GCP_PROJECT_ID").ok(),
+            azure_tenant_id: env::var("AZURE_TENANT_ID").ok(),
+            azure_client_id: env::var("AZURE_CLIENT_ID").ok(),
+            azure_client_secret: env::var("AZURE_CLIENT_SECRET").ok(),
+            azure_subscription_id: env::var("AZURE_SUBSCRIPTION_ID").ok(),
         })
     }
+
+    pub fn required<'a>(value: &'a Option<String>, variable: &str) -> Result<&'a str, Box<dyn std::error::Error>> {
+        match value.as_deref() {
+            Some(value) if !value.trim().is_empty() => Ok(value),
+            _ => Err(SmedError::ConfigInvalid(format!("{} is not set, add it to the env file", variable)).into()),
+        }
+    }
 }
@@ -0,0 +1,701 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::cmd::cloud_provider::{CloudProvider, CloudProviderRegion};
+use crate::error::SmedError;
+use crate::output::{info, warning};
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterSpec {
+    pub name: String,
+    pub provider: CloudProvider,
+    pub region: String,
+    pub image: Option<String>,
+    pub template_dir: Option<String>,
+    pub backend: Backend,
+    pub rke2: Rke2Settings,
+    pub rancher: RancherSettings,
+    pub ssh: SshSettings,
+    pub nodes: NodeGroups,
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshSettings {
+    pub user: String,
+    pub port: u16,
+    pub auth: SshAuth,
+    pub private_key_path: String,
+    pub public_key_path: String,
+    // Defaults to a file in the cluster's state directory, so `smed destroy`
+    // forgets the host keys of its nodes along with the cluster
+    pub known_hosts_path: Option<String>,
+    pub host_key_checking: HostKeyChecking,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SshAuth {
+    Key,
+    Agent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyChecking {
+    Strict,
+    AcceptNew,
+}
+
+// Where Terraform keeps the cluster's state. Anything but `local` lets every
+// teammate with the same spec manage the cluster. Credentials never go in the
+// spec: the S3 backend uses the usual AWS credential chain and the HTTP backend
+// reads TF_HTTP_USERNAME and TF_HTTP_PASSWORD, both can be set in the env file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    Local(LocalBackend),
+    S3(S3Backend),
+    Http(HttpBackend),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalBackend {
+    // Relative to the terraform directory
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Backend {
+    pub bucket: String,
+    // Defaults to smed/<name>/terraform.tfstate
+    pub key: Option<String>,
+    pub region: String,
+    pub profile: Option<String>,
+    // Set for S3-compatible stores like MinIO, usually along with use_path_style
+    pub endpoint: Option<String>,
+    pub use_path_style: bool,
+    // Locks with a lock file next to the state, needs Terraform 1.10 or later
+    pub use_lockfile: bool,
+    pub dynamodb_table: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpBackend {
+    pub address: String,
+    pub lock_address: Option<String>,
+    pub unlock_address: Option<String>,
+    pub lock_method: Option<String>,
+    pub unlock_method: Option<String>,
+}
+
+// The RKE2 release every node installs. With neither set, nodes get whatever
+// the stable channel points at when they are installed, so two clusters
+// deployed a week apart can differ.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rke2Settings {
+    // An exact release like v1.30.4+rke2r1
+    pub version: Option<String>,
+    // A release channel like stable, latest or v1.30
+    pub channel: Option<String>,
+    // Installs from the RKE2 release files staged in this directory instead
+    // of get.rke2.io, for nodes without internet access
+    pub artifacts_dir: Option<String>,
+}
+
+// What the rancher node installs the Rancher server with: a Helm release and
+// the cert-manager and Rancher chart versions, pinned so every deploy gets the
+// same ones. The Rancher version must support the Kubernetes version of RKE2.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RancherSettings {
+    pub helm_version: String,
+    pub cert_manager_version: String,
+    pub version: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeGroups {
+    pub rancher: NodeGroup,
+    pub etcd: NodeGroup,
+    pub control_plane: NodeGroup,
+    pub worker: NodeGroup,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeGroup {
+    pub count: u32,
+    pub instance_type: Option<String>,
+}
+
+impl Default for ClusterSpec {
+    fn default() -> Self {
+        Self {
+            name: "smed".to_string(),
+            provider: CloudProvider::AWS,
+            region: "us-east-1".to_string(),
+            image: None,
+            template_dir: None,
+            backend: Backend::default(),
+            rke2: Rke2Settings::default(),
+            rancher: RancherSettings::default(),
+            ssh: SshSettings::default(),
+            nodes: NodeGroups::default(),
+            tags: BTreeMap::from([("Project".to_string(), "smed".to_string())]),
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Local(LocalBackend::default())
+    }
+}
+
+impl Default for LocalBackend {
+    fn default() -> Self {
+        Self { path: "terraform.tfstate".to_string() }
+    }
+}
+
+impl Default for S3Backend {
+    fn default() -> Self {
+        Self {
+            bucket: String::new(),
+            key: None,
+            region: "us-east-1".to_string(),
+            profile: None,
+            endpoint: None,
+            use_path_style: false,
+            use_lockfile: true,
+            dynamodb_table: None,
+        }
+    }
+}
+
+impl Default for RancherSettings {
+    fn default() -> Self {
+        Self {
+            helm_version: "v3.18.6".to_string(),
+            cert_manager_version: "v1.18.2".to_string(),
+            version: "2.12.1".to_string(),
+        }
+    }
+}
+
+impl Default for SshSettings {
+    fn default() -> Self {
+        Self {
+            user: "ubuntu".to_string(),
+            port: 22,
+            auth: SshAuth::Key,
+            private_key_path: "~/.ssh/id_rsa".to_string(),
+            public_key_path: "~/.ssh/id_rsa.pub".to_string(),
+            known_hosts_path: None,
+            host_key_checking: HostKeyChecking::AcceptNew,
+        }
+    }
+}
+
+impl Default for NodeGroups {
+    fn default() -> Self {
+        Self {
+            rancher: NodeGroup::new(1),
+            etcd: NodeGroup::new(1),
+            control_plane: NodeGroup::new(1),
+            worker: NodeGroup::new(2),
+        }
+    }
+}
+
+impl NodeGroup {
+    fn new(count: u32) -> Self {
+        Self { count, instance_type: None }
+    }
+}
+
+impl ClusterSpec {
+    // Loads the spec at `path` and, for an environment, merges the overlay
+    // `<stem>.<env>.yaml` next to it on top. Unless the overlay names the
+    // cluster, it is called `<name>-<env>` so the resources and kubeconfig
+    // context of different environments never clash. `defaults` holds what
+    // `smed init` was run with, used for the keys neither file sets.
+    pub fn load(path: &str, env: Option<&str>, defaults: Mapping) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Path::new(path);
+
+        let mut value = Self::read(path)?.unwrap_or_else(|| {
+            warning!("⚠ No cluster spec found at {}, using defaults", path.display());
+            Value::Mapping(Mapping::new())
+        });
+
+        let mut named = false;
+        let mut overlay_backend = None;
+
+        if let Some(env) = env {
+            let overlay_path = Self::overlay_path(path, env);
+
+            match Self::read(&overlay_path)? {
+                Some(overlay) => {
+                    named = overlay.get("name").is_some();
+                    overlay_backend = overlay.get("backend").cloned();
+
+                    // A backend of another type shares no settings with the
+                    // base one, so none of them carry over
+                    let backend_type = |spec: &Value| spec.get("backend").and_then(|backend| backend.get("type")).cloned();
+                    let switched = backend_type(&overlay).is_some_and(|overlay_type| backend_type(&value) != Some(overlay_type));
+                    if let Some(base) = value.as_mapping_mut().filter(|_| switched) {
+                        base.remove("backend");
+                    }
+
+                    merge(&mut value, overlay);
+                },
+                None => info!("No spec overlay for {} at {}, using {} as is", env, overlay_path.display(), path.display()),
+            }
+        }
+
+        for (key, default) in &defaults {
+            if let Some(value) = value.get(key).filter(|value| *value != default) {
+                warning!("⚠ The spec sets {} to {} but `smed init` was run with {}, using the spec's", key.as_str().unwrap_or_default(), value.as_str().unwrap_or_default(), default.as_str().unwrap_or_default());
+            }
+        }
+
+        let mut merged = Value::Mapping(defaults);
+        merge(&mut merged, value);
+
+        let mut spec: ClusterSpec = serde_yaml::from_value(merged)
+            .map_err(|e| SmedError::ConfigInvalid(format!("Invalid cluster spec {}: {}", path.display(), e)))?;
+
+        if let Some(env) = env.filter(|_| !named) {
+            spec.name = format!("{}-{}", spec.name, env);
+        }
+
+        // The default s3 key follows the name, but a location written in the
+        // shared spec would give every environment the same state
+        let shared_location = match &spec.backend {
+            Backend::S3(s3) if s3.key.is_some() => Some("key"),
+            Backend::Http(_) => Some("address"),
+            Backend::Local(local) if Path::new(&local.path).is_absolute() => Some("path"),
+            _ => None,
+        };
+        let shared_location = shared_location.filter(|location| overlay_backend.as_ref().and_then(|backend| backend.get(location)).is_none());
+
+        if let (Some(env), Some(location)) = (env, shared_location) {
+            return Err(SmedError::ConfigInvalid(format!(
+                "backend.{} in {} would keep the state of every environment in one place, set the one of {} in {}",
+                location, path.display(), env, Self::overlay_path(path, env).display(),
+            )).into());
+        }
+
+        spec.validate()?;
+
+        Ok(spec)
+    }
+
+    fn read(path: &Path) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path)?;
+
+        let value: Value = serde_yaml::from_str(&contents)
+            .map_err(|e| SmedError::ConfigInvalid(format!("Invalid cluster spec {}: {}", path.display(), e)))?;
+
+        Ok(Some(if value.is_null() { Value::Mapping(Mapping::new()) } else { value }))
+    }
+
+    // smed.yaml -> smed.<env>.yaml
+    fn overlay_path(path: &Path, env: &str) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+        match path.extension() {
+            Some(extension) => path.with_file_name(format!("{}.{}.{}", stem, env, extension.to_string_lossy())),
+            None => path.with_file_name(format!("{}.{}", stem, env)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            errors.push(format!("name '{}' must be non-empty and only contain lowercase letters, digits and '-'", self.name));
+        }
+
+        if let Err(e) = CloudProviderRegion::from_str(&self.region) {
+            errors.push(e);
+        }
+
+        for (field, value) in [("ssh.user", &self.ssh.user), ("ssh.private_key_path", &self.ssh.private_key_path), ("ssh.public_key_path", &self.ssh.public_key_path)] {
+            if value.trim().is_empty() {
+                errors.push(format!("{} must not be empty", field));
+            }
+        }
+
+        if self.ssh.known_hosts_path.as_deref().is_some_and(|path| path.trim().is_empty()) {
+            errors.push("ssh.known_hosts_path must not be empty".to_string());
+        }
+
+        if self.image.as_deref().is_some_and(|image| image.trim().is_empty()) {
+            errors.push("image must not be empty".to_string());
+        }
+
+        if self.provider == CloudProvider::AZURE && self.image.as_deref().is_some_and(|image| image.split(':').count() != 4) {
+            errors.push("image must be an Azure URN in the form publisher:offer:sku:version".to_string());
+        }
+
+        if self.template_dir.as_deref().is_some_and(|directory| directory.trim().is_empty()) {
+            errors.push("template_dir must not be empty".to_string());
+        }
+
+        errors.extend(self.backend.errors());
+        errors.extend(self.rke2.errors());
+        errors.extend(self.rancher.errors());
+
+        for (role, group) in self.nodes.roles() {
+            if group.instance_type.as_deref().is_some_and(|instance_type| instance_type.trim().is_empty()) {
+                errors.push(format!("nodes.{}.instance_type must not be empty", role));
+            }
+        }
+
+        if self.nodes.rancher.count != 1 {
+            errors.push(format!("nodes.rancher.count must be 1, got {}", self.nodes.rancher.count));
+        }
+
+        // An odd number of etcd members keeps a quorum through the loss of one
+        // (3) or two (5) of them, control-plane servers come in the same sizes
+        for (role, group) in [("etcd", &self.nodes.etcd), ("control_plane", &self.nodes.control_plane)] {
+            if ![1, 3, 5].contains(&group.count) {
+                errors.push(format!("nodes.{}.count must be 1, 3 or 5, got {}", role, group.count));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SmedError::ConfigInvalid(format!("Invalid cluster spec:\n  - {}", errors.join("\n  - "))).into())
+        }
+    }
+
+    pub fn region(&self) -> Result<&'static str, Box<dyn std::error::Error>> {
+        let region = CloudProviderRegion::from_str(&self.region).map_err(SmedError::ConfigInvalid)?;
+
+        Ok(region.name_for(&self.provider))
+    }
+
+    pub fn image(&self) -> Option<String> {
+        self.image.clone().or_else(|| self.provider.default_image().map(str::to_string))
+    }
+
+    pub fn instance_type(&self, role: &str, group: &NodeGroup) -> String {
+        group.instance_type.clone().unwrap_or_else(|| self.provider.default_instance_type(role).to_string())
+    }
+}
+
+// Merges `overlay` into `base`: mappings key by key, anything else replaced.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl Backend {
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        // Fields that must not be empty when they are set
+        let fields: Vec<(&str, Option<&String>)> = match self {
+            Backend::Local(local) => vec![("backend.path", Some(&local.path))],
+            Backend::S3(s3) => vec![
+                ("backend.bucket", Some(&s3.bucket)),
+                ("backend.region", Some(&s3.region)),
+                ("backend.key", s3.key.as_ref()),
+                ("backend.endpoint", s3.endpoint.as_ref()),
+                ("backend.dynamodb_table", s3.dynamodb_table.as_ref()),
+            ],
+            Backend::Http(http) => {
+                if http.lock_address.is_some() != http.unlock_address.is_some() {
+                    errors.push("backend.lock_address and backend.unlock_address must be set together".to_string());
+                }
+
+                vec![
+                    ("backend.address", Some(&http.address)),
+                    ("backend.lock_address", http.lock_address.as_ref()),
+                    ("backend.unlock_address", http.unlock_address.as_ref()),
+                ]
+            },
+        };
+
+        for (field, value) in fields {
+            if value.is_some_and(|value| value.trim().is_empty()) {
+                errors.push(format!("{} must not be empty", field));
+            }
+        }
+
+        errors
+    }
+}
+
+impl RancherSettings {
+    fn errors(&self) -> Vec<String> {
+        let valid = |version: &str| !version.is_empty() && version.chars().all(|c| c.is_ascii_alphanumeric() || ".-+".contains(c));
+
+        [
+            ("rancher.helm_version", &self.helm_version, "a Helm release like v3.18.6"),
+            ("rancher.cert_manager_version", &self.cert_manager_version, "a cert-manager chart version like v1.18.2"),
+            ("rancher.version", &self.version, "a Rancher chart version like 2.12.1"),
+        ]
+            .into_iter()
+            .filter(|(_, version, _)| !valid(version))
+            .map(|(field, version, example)| format!("{} '{}' must be {}", field, version, example))
+            .collect()
+    }
+}
+
+impl Rke2Settings {
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.version.is_some() && self.channel.is_some() {
+            errors.push("rke2.version and rke2.channel can't both be set".to_string());
+        }
+
+        if let Some(version) = self.version.as_deref().filter(|version| !is_rke2_version(version)) {
+            errors.push(format!("rke2.version '{}' must be an RKE2 release like v1.30.4+rke2r1", version));
+        }
+
+        let valid_channel = |channel: &str| !channel.is_empty() && channel.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || ".-".contains(c));
+
+        if let Some(channel) = self.channel.as_deref().filter(|channel| !valid_channel(channel)) {
+            errors.push(format!("rke2.channel '{}' must be a channel like stable, latest or v1.30", channel));
+        }
+
+        if self.artifacts_dir.as_deref().is_some_and(|directory| directory.trim().is_empty()) {
+            errors.push("rke2.artifacts_dir must not be empty".to_string());
+        }
+
+        if self.artifacts_dir.is_some() && self.channel.is_some() {
+            errors.push("rke2.channel can't be used with rke2.artifacts_dir, the artifacts are a single release".to_string());
+        }
+
+        errors
+    }
+}
+
+// RKE2 releases look like v1.30.4+rke2r1, the string INSTALL_RKE2_VERSION takes.
+pub fn is_rke2_version(version: &str) -> bool {
+    version.len() > 1
+        && version.starts_with('v')
+        && version.chars().all(|c| c.is_ascii_alphanumeric() || ".+-".contains(c))
+}
+
+impl NodeGroups {
+    pub fn roles(&self) -> [(&'static str, &NodeGroup); 4] {
+        [
+            ("rancher", &self.rancher),
+            ("etcd", &self.etcd),
+            ("control_plane", &self.control_plane),
+            ("worker", &self.worker),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_spec_uses_defaults() {
+        let spec: ClusterSpec = serde_yaml::from_str("
+name: staging
+nodes:
+  worker:
+    count: 5
+    instance_type: t3.large
+").unwrap();
+
+        assert_eq!(spec.name, "staging");
+        assert_eq!(spec.region, "us-east-1");
+        assert_eq!(spec.nodes.worker.count, 5);
+        assert_eq!(spec.instance_type("rancher", &spec.nodes.rancher), "t2.medium");
+        assert!(spec.validate().is_ok());
+    }
+
+    #[test]
+    fn test_etcd_and_control_plane_counts_allow_ha() {
+        let spec: ClusterSpec = serde_yaml::from_str("nodes:\n  etcd:\n    count: 3\n  control_plane:\n    count: 5\n").unwrap();
+        assert!(spec.validate().is_ok());
+
+        let spec: ClusterSpec = serde_yaml::from_str("nodes:\n  etcd:\n    count: 2\n  rancher:\n    count: 3\n").unwrap();
+        let error = spec.validate().unwrap_err().to_string();
+
+        assert!(error.contains("nodes.etcd.count must be 1, 3 or 5, got 2"));
+        assert!(error.contains("nodes.rancher.count must be 1, got 3"));
+    }
+
+    #[test]
+    fn test_provider_defaults_follow_the_provider() {
+        let spec: ClusterSpec = serde_yaml::from_str("
+provider: gcp
+region: us-east-2
+").unwrap();
+
+        assert_eq!(spec.region().unwrap(), "us-east4");
+        assert_eq!(spec.image().unwrap(), "ubuntu-os-cloud/ubuntu-2204-lts");
+        assert_eq!(spec.instance_type("worker", &spec.nodes.worker), "e2-small");
+    }
+
+    #[test]
+    fn test_backend_is_read_from_the_spec() {
+        let spec: ClusterSpec = serde_yaml::from_str("
+backend:
+  type: s3
+  bucket: smed-state
+  endpoint: http://127.0.0.1:9000
+  use_path_style: true
+").unwrap();
+
+        let Backend::S3(s3) = &spec.backend else { panic!("expected an s3 backend") };
+        assert_eq!(s3.region, "us-east-1");
+        assert!(s3.use_lockfile);
+        assert!(spec.validate().is_ok());
+
+        let spec: ClusterSpec = serde_yaml::from_str("
+backend:
+  type: http
+  address: ''
+  lock_address: http://127.0.0.1:8080/lock
+").unwrap();
+
+        let error = spec.validate().unwrap_err().to_string();
+        assert!(error.contains("backend.address must not be empty"));
+        assert!(error.contains("must be set together"));
+
+        assert!(serde_yaml::from_str::<ClusterSpec>("backend:\n  type: consul\n").is_err());
+        assert_eq!(ClusterSpec::default().backend, Backend::Local(LocalBackend::default()));
+    }
+
+    #[test]
+    fn test_environments_never_share_a_backend_location() {
+        let directory = std::env::temp_dir().join(format!("smed-spec-backend-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join("smed.yaml").display().to_string();
+        let load = |env| ClusterSpec::load(&path, env, Mapping::new());
+
+        fs::write(directory.join("smed.yaml"), "name: shop\nbackend:\n  type: s3\n  bucket: smed-state\n").unwrap();
+        let Backend::S3(s3) = load(Some("staging")).unwrap().backend else { panic!("expected an s3 backend") };
+        assert_eq!(s3.key, None);
+
+        fs::write(directory.join("smed.yaml"), "name: shop\nbackend:\n  type: http\n  address: https://state.example.com/shop\n").unwrap();
+        assert!(load(None).is_ok());
+
+        let error = load(Some("staging")).unwrap_err().to_string();
+        assert!(error.contains("backend.address"), "{}", error);
+        assert!(error.contains("smed.staging.yaml"), "{}", error);
+
+        fs::write(directory.join("smed.staging.yaml"), "backend:\n  address: https://state.example.com/shop-staging\n").unwrap();
+        let Backend::Http(http) = load(Some("staging")).unwrap().backend else { panic!("expected an http backend") };
+        assert_eq!(http.address, "https://state.example.com/shop-staging");
+
+        fs::remove_file(directory.join("smed.staging.yaml")).unwrap();
+        fs::write(directory.join("smed.yaml"), "name: shop\nbackend:\n  type: s3\n  bucket: smed-state\n  key: shop.tfstate\n").unwrap();
+        assert!(load(Some("staging")).unwrap_err().to_string().contains("backend.key"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_of_another_backend_type_replaces_the_backend() {
+        let directory = std::env::temp_dir().join(format!("smed-spec-backend-type-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join("smed.yaml").display().to_string();
+        let load = |env| ClusterSpec::load(&path, env, Mapping::new());
+
+        fs::write(directory.join("smed.yaml"), "name: shop\nbackend:\n  type: s3\n  bucket: smed-state\n").unwrap();
+        fs::write(directory.join("smed.staging.yaml"), "backend:\n  type: local\n  path: staging.tfstate\n").unwrap();
+        assert_eq!(load(Some("staging")).unwrap().backend, Backend::Local(LocalBackend { path: "staging.tfstate".to_string() }));
+
+        // The same type still merges field by field
+        fs::write(directory.join("smed.staging.yaml"), "backend:\n  type: s3\n  key: staging.tfstate\n").unwrap();
+        let Backend::S3(s3) = load(Some("staging")).unwrap().backend else { panic!("expected an s3 backend") };
+        assert_eq!((s3.bucket.as_str(), s3.key.as_deref()), ("smed-state", Some("staging.tfstate")));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_rancher_install_is_pinned() {
+        let spec = ClusterSpec::default();
+        assert_eq!(spec.rancher, RancherSettings::default());
+        assert!(spec.validate().is_ok());
+
+        let spec: ClusterSpec = serde_yaml::from_str("rancher:\n  version: 2.11.3\n").unwrap();
+        assert_eq!(spec.rancher.version, "2.11.3");
+        assert_eq!(spec.rancher.helm_version, RancherSettings::default().helm_version);
+
+        let spec: ClusterSpec = serde_yaml::from_str("rancher:\n  helm_version: ''\n  cert_manager_version: v1.18; curl evil\n").unwrap();
+        let error = spec.validate().unwrap_err().to_string();
+        assert!(error.contains("rancher.helm_version '' must be"));
+        assert!(error.contains("rancher.cert_manager_version 'v1.18; curl evil' must be"));
+    }
+
+    #[test]
+    fn test_rke2_version_or_channel_is_pinned() {
+        let spec: ClusterSpec = serde_yaml::from_str("rke2:\n  version: v1.30.4+rke2r1\n").unwrap();
+        assert_eq!(spec.rke2.version.as_deref(), Some("v1.30.4+rke2r1"));
+        assert!(spec.validate().is_ok());
+
+        let spec: ClusterSpec = serde_yaml::from_str("rke2:\n  version: 1.30'; reboot\n  channel: Stable\n").unwrap();
+        let error = spec.validate().unwrap_err().to_string();
+
+        assert!(error.contains("can't both be set"));
+        assert!(error.contains("rke2.version '1.30'; reboot'"));
+        assert!(error.contains("rke2.channel 'Stable'"));
+
+        let spec: ClusterSpec = serde_yaml::from_str("rke2:\n  channel: stable\n  artifacts_dir: ./rke2\n").unwrap();
+        assert!(spec.validate().unwrap_err().to_string().contains("can't be used with rke2.artifacts_dir"));
+    }
+
+    #[test]
+    fn test_invalid_spec_reports_every_error() {
+        let spec: ClusterSpec = serde_yaml::from_str("
+name: Bad Name
+region: mars-1
+nodes:
+  etcd:
+    count: 0
+    instance_type: ''
+").unwrap();
+
+        let error = spec.validate().unwrap_err().to_string();
+
+        assert!(error.contains("name 'Bad Name'"));
+        assert!(error.contains("Unknown region: mars-1"));
+        assert!(error.contains("nodes.etcd.instance_type"));
+        assert!(error.contains("nodes.etcd.count must be 1, 3 or 5, got 0"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// The failures scripts wrapping smed may want to tell apart. Each has its own
+// process exit code; any other error exits with 1.
+#[derive(Debug)]
+pub enum SmedError {
+    ToolMissing(String),
+    AuthFailed { provider: String, message: String },
+    TerraformFailed(String),
+    SshStepFailed(String),
+    ConfigInvalid(String),
+    OutputMissing(String),
+    // The cluster answered, but not every node is healthy
+    Unhealthy(String),
+}
+
+impl SmedError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SmedError::ConfigInvalid(_) => 2,
+            SmedError::ToolMissing(_) => 3,
+            SmedError::AuthFailed { .. } => 4,
+            SmedError::TerraformFailed(_) => 5,
+            SmedError::SshStepFailed(_) => 6,
+            SmedError::OutputMissing(_) => 7,
+            SmedError::Unhealthy(_) => 8,
+        }
+    }
+
+    // Turns the error of starting `tool` into ToolMissing when it isn't installed.
+    pub fn spawn(tool: &str, error: io::Error) -> Box<dyn Error> {
+        if error.kind() == io::ErrorKind::NotFound {
+            Box::new(SmedError::ToolMissing(tool.to_string()))
+        } else {
+            Box::new(error)
+        }
+    }
+}
+
+impl fmt::Display for SmedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmedError::ToolMissing(tool) => write!(f, "{} is not installed or not in PATH, install it and try again", tool),
+            SmedError::AuthFailed { provider, message } => write!(f, "Authentication to {} failed: {}\nCheck the credentials in the env file", provider, message.trim()),
+            SmedError::TerraformFailed(command) => write!(f, "terraform {} failed, see the Terraform output above", command),
+            SmedError::SshStepFailed(message) => write!(f, "{}", message),
+            SmedError::ConfigInvalid(message) => write!(f, "{}", message),
+            SmedError::Unhealthy(message) => write!(f, "{}", message),
+            SmedError::OutputMissing(key) => write!(f, "Terraform output '{}' is missing, run `smed deploy` first or check that the template defines it", key),
+        }
+    }
+}
+
+impl Error for SmedError {}
+
+pub fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    error.downcast_ref::<SmedError>().map_or(1, SmedError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let missing: Box<dyn Error> = SmedError::spawn("terraform", io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(exit_code(missing.as_ref()), 3);
+        assert_eq!(missing.to_string(), "terraform is not installed or not in PATH, install it and try again");
+
+        let denied: Box<dyn Error> = SmedError::spawn("terraform", io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(exit_code(denied.as_ref()), 1);
+
+        let invalid: Box<dyn Error> = SmedError::ConfigInvalid("bad spec".to_string()).into();
+        assert_eq!(exit_code(invalid.as_ref()), 2);
+
+        let unhealthy: Box<dyn Error> = SmedError::Unhealthy("1 of 5 node(s) are unhealthy".to_string()).into();
+        assert_eq!(exit_code(unhealthy.as_ref()), 8);
+    }
+}
@@ -1,13 +1,41 @@
 mod cli;
 mod cmd;
 mod config;
+mod error;
+mod output;
+
+use std::env;
+use std::time::Instant;
+
+use output::Level;
 
 fn main() {    
     let cli = cli::build_cli().get_matches();
 
+    output::set_json(cli.get_one::<String>("output").is_some_and(|format| format == "json"));
+
+    output::set_level(match (cli.get_flag("quiet"), cli.get_flag("verbose")) {
+        (true, _) => Level::Warn,
+        (_, true) => Level::Debug,
+        _ => Level::Info,
+    });
+
+    // https://no-color.org: any non-empty NO_COLOR disables colors
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    output::set_color(!cli.get_flag("no-color") && !no_color);
+
+    let started = Instant::now();
+    let command = cli.subcommand_name().unwrap_or_default().to_string();
+
+    let result = match cli.get_one::<String>("log-file") {
+        Some(path) => output::set_log_file(path),
+        None => Ok(()),
+    }.and_then(|_| cmd::run(cli));
+
+    output::finish(&command, &result, started);
 
-    if let Err(e) = cmd::run(cli) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+    if let Err(e) = result {
+        output::error!("Error: {}", e);
+        std::process::exit(error::exit_code(e.as_ref()));
     }
-}
\ No newline at end of file
+}
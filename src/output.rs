@@ -0,0 +1,264 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::error::{self as errors, SmedError};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+// Values never printed or logged, like the cluster join token.
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// The result printed by `finish` with `--output json`. Commands add to it as
+// they go, so a failed command still reports how far it got.
+static RESULT: LazyLock<Mutex<Map<String, Value>>> = LazyLock::new(|| Mutex::new(Map::new()));
+
+// How much is printed: `-q` only shows warnings and errors, `-v` adds the
+// commands smed runs and everything they print. The log file always gets
+// everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::output::log($crate::output::Level::Error, Some($crate::output::Color::Red), &format!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::output::log($crate::output::Level::Warn, Some($crate::output::Color::Yellow), &format!($($arg)*))
+    };
+}
+
+// Progress worth highlighting, like a step that finished.
+macro_rules! success {
+    ($($arg:tt)*) => {
+        $crate::output::log($crate::output::Level::Info, Some($crate::output::Color::Green), &format!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::log($crate::output::Level::Info, None, &format!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::output::log($crate::output::Level::Debug, None, &format!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, success, warning};
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn level_enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+// Appends every message to `path` as one JSON object per line, whatever the
+// verbosity.
+pub fn set_log_file(path: &str) -> Result<(), Box<dyn Error>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| SmedError::ConfigInvalid(format!("Failed to open log file {}: {}", path, e)))?;
+
+    *LOG_FILE.lock().unwrap() = Some(file);
+
+    Ok(())
+}
+
+pub fn add_secret(secret: &str) {
+    let mut secrets = SECRETS.lock().unwrap();
+
+    if !secret.is_empty() && !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+pub fn redact(text: &str) -> String {
+    SECRETS.lock().unwrap().iter()
+        .fold(text.to_string(), |text, secret| text.replace(secret.as_str(), "********"))
+}
+
+// Prints `message` like `info!` but never to the log file, for what may only
+// be shown once on the terminal, like the initial Rancher password.
+pub fn show(message: &str) {
+    if level_enabled(Level::Info) {
+        writeln!(human(), "{}", message).ok();
+    }
+}
+
+// Wraps `text` in the ANSI escapes for `color`, unless colors are disabled.
+pub fn paint(color: Color, text: &str) -> String {
+    if !COLOR.load(Ordering::Relaxed) {
+        return text.to_string();
+    }
+
+    let code = match color {
+        Color::Red => 31,
+        Color::Green => 32,
+        Color::Yellow => 33,
+    };
+
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+// Writes a message to the log file and, when `level` is enabled, to the
+// terminal: warnings and errors on stderr, everything else where `human`
+// points.
+pub fn log(level: Level, color: Option<Color>, message: &str) {
+    let message = redact(message);
+
+    write_log_file(level, &message);
+
+    if !level_enabled(level) {
+        return;
+    }
+
+    let text = match color {
+        Some(color) => paint(color, &message),
+        None => message,
+    };
+
+    let mut out: Box<dyn Write> = if level <= Level::Warn { Box::new(io::stderr()) } else { human() };
+    writeln!(out, "{}", text).ok();
+}
+
+fn write_log_file(level: Level, message: &str) {
+    let mut file = LOG_FILE.lock().unwrap();
+
+    if let Some(file) = file.as_mut() {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let line = json!({ "timestamp_ms": timestamp_ms, "level": level.name(), "message": strip_colors(message) });
+
+        writeln!(file, "{}", line).ok();
+    }
+}
+
+fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+// Where human readable progress goes: stdout, or stderr with `--output json`
+// so stdout only carries the JSON result.
+pub fn human() -> Box<dyn Write> {
+    if is_json() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+pub fn record(key: &str, value: impl Serialize) {
+    let value = serde_json::to_value(value).unwrap_or(Value::Null);
+
+    RESULT.lock().unwrap().insert(key.to_string(), value);
+}
+
+pub fn timing(name: &str, started: Instant) {
+    let mut result = RESULT.lock().unwrap();
+
+    let timings = result.entry("timings_ms").or_insert_with(|| Value::Object(Map::new()));
+    timings[name] = Value::from(started.elapsed().as_millis() as u64);
+}
+
+// With `--output json`, prints everything recorded by the command as a single
+// JSON object on stdout, along with whether it succeeded.
+pub fn finish(command: &str, outcome: &Result<(), Box<dyn Error>>, started: Instant) {
+    if !is_json() {
+        return;
+    }
+
+    timing("total", started);
+
+    let mut result = RESULT.lock().unwrap();
+    result.insert("command".to_string(), Value::from(command));
+
+    match outcome {
+        Ok(()) => {
+            result.insert("status".to_string(), Value::from("ok"));
+        },
+        Err(e) => {
+            result.insert("status".to_string(), Value::from("error"));
+            result.insert("error".to_string(), Value::from(e.to_string()));
+            result.insert("exit_code".to_string(), Value::from(errors::exit_code(e.as_ref())));
+        },
+    }
+
+    println!("{}", Value::Object(result.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_and_colors_are_kept_out_of_the_log() {
+        add_secret("s3cr3t");
+
+        assert_eq!(redact("token: s3cr3t"), "token: ********");
+        assert_eq!(strip_colors(&format!("\x1b[32m{}\x1b[0m ok", "✔ ready")), "✔ ready ok");
+    }
+}
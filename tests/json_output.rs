@@ -0,0 +1,79 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use serde_json::Value;
+
+// Runs the smed binary in `directory` with every command answered from
+// `fixture`, like `SMED_REPLAY=<fixture> smed <args>`.
+fn smed(directory: &Path, fixture: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_smed"))
+        .args(args)
+        .current_dir(directory)
+        .env("SMED_REPLAY", Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(fixture))
+        .env_remove("SMED_RECORD")
+        .output()
+        .unwrap()
+}
+
+fn project(name: &str, spec: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("smed-json-{}-{}", name, std::process::id()));
+    fs::create_dir_all(directory.join("terraform/.smed")).unwrap();
+
+    fs::write(directory.join("smed.yaml"), spec).unwrap();
+    fs::write(directory.join(".env"), "AWS_ACCESS_KEY_ID=test_access_key\nAWS_SECRET_ACCESS_KEY=test_secret_key\n").unwrap();
+    fs::write(directory.join("terraform/.smed/rancher-password"), "fixture-password").unwrap();
+
+    directory
+}
+
+#[test]
+fn test_deploy_prints_one_json_result_on_stdout_and_progress_on_stderr() {
+    let directory = project("deploy", "name: fixture\nnodes:\n  worker:\n    count: 1\n");
+
+    let log_file = directory.join("smed.log");
+    let output = smed(&directory, "deploy_aws.json", &["--output", "json", "--no-color", "--log-file", log_file.to_str().unwrap(), "deploy"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stdout.lines().count(), 1);
+
+    let result: Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(result["command"], "deploy");
+    assert_eq!(result["status"], "ok");
+    assert!(result.get("exit_code").is_none());
+    assert!(result["nodes"].as_array().unwrap().iter().all(|node| node["status"] == "ready"));
+    assert_eq!(result["nodes"].as_array().unwrap().len(), 4);
+
+    for timing in ["terraform", "bootstrap", "total"] {
+        assert!(result["timings_ms"][timing].is_u64(), "missing timing {}", timing);
+    }
+
+    assert!(stderr.contains("Rancher dashboard: https://54.0.0.1.sslip.io"));
+    assert!(!stdout.contains("Rancher dashboard"));
+
+    // The password is shown once, the log file only says where it is saved
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(stderr.contains("Password: fixture-password"));
+    assert!(!log.contains("fixture-password"));
+    assert!(log.contains("rancher-password"));
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_failed_command_reports_its_error_and_exit_code() {
+    let directory = project("failed", "name: Bad Name\n");
+
+    let output = smed(&directory, "deploy_aws.json", &["--output", "json", "deploy"]);
+    let result: Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(result["status"], "error");
+    assert_eq!(result["exit_code"], 2);
+    assert!(result["error"].as_str().unwrap().contains("name 'Bad Name'"));
+
+    fs::remove_dir_all(&directory).unwrap();
+}